@@ -0,0 +1,132 @@
+//! Catalog module keeps an opt-in catalog of the keys written, as the `anna` protocol has no
+//! way to scan or list keys.
+//!
+//! Keys are grouped by their top level prefix, the part of the key up to and including the
+//! first '/' (or "" for keys without one). Each key written is added to one of the SET lattice
+//! shards of the catalog of its prefix, chosen by a hash of the key, and the prefix is added to
+//! a SET lattice of all the prefixes. The catalog records keys that have been written, some of
+//! which may since have been deleted, so deleted keys are left out when listing keys.
+//!
+//! Keys are registered by the commands and data structures that write them, rather than by each
+//! lattice write, so that only the keys users name are listed, and not the keys that data
+//! structures keep their state in (such as counter segments or document fields).
+use std::collections::{BTreeSet, HashSet};
+
+use log::debug;
+
+use crate::errors::*;
+use crate::kvs_client::{default_if_missing, KVSClient, Key, CLIENT_METADATA_PREFIX};
+
+// The number of shards the catalog of each prefix is split into. Readers and writers must agree.
+const SHARDS: u32 = 16;
+
+/// `Catalog` registers the keys written by a client
+#[derive(Default)]
+pub struct Catalog {
+    // the prefixes this client has already added to the set of prefixes
+    registered: HashSet<String>,
+}
+
+/*
+    Return the top level prefix of `key`
+ */
+fn prefix_of(key: &str) -> &str {
+    match key.find('/') {
+        Some(index) => &key[..=index],
+        None => "",
+    }
+}
+
+/*
+    Return the shard of its prefix's catalog that `key` is stored in. This uses FNV-1a as the hash
+    must be the same for all clients.
+ */
+fn shard_of(key: &str) -> u32 {
+    let hash = key.bytes().fold(0x811c9dc5_u32, |hash, byte| (hash ^ byte as u32).wrapping_mul(0x01000193));
+    hash % SHARDS
+}
+
+fn shard_key(prefix: &str, shard: u32) -> Key {
+    format!("{}catalog|{}|{}", CLIENT_METADATA_PREFIX, prefix, shard)
+}
+
+fn prefixes_key() -> Key {
+    format!("{}catalog|prefixes", CLIENT_METADATA_PREFIX)
+}
+
+impl KVSClient {
+    /// Add `key` to the catalog, if the catalog is enabled for this client. Reserved keys are
+    /// never added.
+    pub fn register_key(&mut self, key: &str) -> Result<()> {
+        if key.starts_with(CLIENT_METADATA_PREFIX) {
+            return Ok(());
+        }
+        let prefix = prefix_of(key).to_string();
+        let new_prefix = match self.get_catalog() {
+            Some(catalog) => catalog.registered.insert(prefix.clone()),
+            None => return Ok(()),
+        };
+
+        if new_prefix {
+            debug!("Registering prefix '{}' in the catalog", prefix);
+            self.add_to_set(&prefixes_key(), vec!(prefix.as_bytes().to_vec()))?;
+        }
+        self.add_to_set(&shard_key(&prefix, shard_of(key)), vec!(key.as_bytes().to_vec()))
+            .chain_err(|| format!("Could not add key '{}' to the catalog", key))
+    }
+
+    /// Return the keys in the catalog that start with `prefix`, other than deleted keys
+    pub fn list_keys(&mut self, prefix: &str) -> Result<BTreeSet<Key>> {
+        // a prefix including a '/' can only match keys with the same top level prefix
+        let prefixes = match prefix.find('/') {
+            Some(_) => vec!(prefix_of(prefix).to_string()),
+            None => default_if_missing(self.get_set_values(&prefixes_key()))?.iter()
+                .map(|top| String::from_utf8_lossy(top).to_string())
+                .filter(|top| top.is_empty() || top.starts_with(prefix))
+                .collect(),
+        };
+
+        let mut keys = BTreeSet::new();
+        for top in prefixes {
+            for shard in 0..SHARDS {
+                keys.extend(default_if_missing(self.get_set_values(&shard_key(&top, shard)))?.iter()
+                    .map(|key| String::from_utf8_lossy(key).to_string())
+                    .filter(|key| key.starts_with(prefix)));
+            }
+        }
+
+        let deleted = self.deleted_keys(&keys)?;
+        keys.retain(|key| !deleted.contains(key));
+        Ok(keys)
+    }
+
+    /*
+        KEYS <prefix>
+
+        Each key is displayed on its own line, in order
+     */
+    pub fn keys(&mut self, tokens: &[&str]) -> Result<String> {
+        debug!("KEYS: {:?}", tokens);
+        let prefix = tokens.first().unwrap_or(&"");
+        Ok(self.list_keys(prefix)?.into_iter().collect::<Vec<Key>>().join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{prefix_of, shard_of, SHARDS};
+
+    #[test]
+    fn top_level_prefix() {
+        assert_eq!(prefix_of("orders/2021/42"), "orders/");
+        assert_eq!(prefix_of("counter"), "");
+    }
+
+    #[test]
+    fn shards_are_stable() {
+        // changing the hash would hide keys registered by other clients
+        assert_eq!(shard_of("orders/42"), shard_of("orders/42"));
+        assert_eq!(shard_of(""), 0x811c9dc5 % SHARDS);
+        assert!((0..100).map(|n| shard_of(&format!("orders/{}", n))).all(|shard| shard < SHARDS));
+    }
+}
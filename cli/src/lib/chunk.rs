@@ -0,0 +1,166 @@
+//! Chunk module splits large LWW values into chunks, so that no single `KeyTuple` payload
+//! holds a large value.
+//!
+//! Values longer than the client's chunk threshold are split into chunks, each stored in an LWW
+//! lattice key named after the SHA-256 hash of its content. The key of the value holds a
+//! manifest listing the chunks, and the length and hash of the whole value. Reads fetch the
+//! chunks in a batch, reassemble the value and verify the hashes of the chunks and the value.
+//! As chunks are content-addressed, writing the same content twice reuses its chunks.
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::errors::*;
+use crate::kvs_client::{decode_tuple, KVSClient, Key, CLIENT_METADATA_PREFIX};
+use crate::proto::anna::{LatticeType, LwwValue};
+
+/// Values longer than this, in bytes, are chunked by default
+pub const DEFAULT_CHUNK_THRESHOLD: usize = 1024 * 1024;
+
+// Marks the start of a manifest, which follows it as JSON
+const MANIFEST_MAGIC: &[u8] = b"\xA7C";
+
+/*
+    The manifest of a chunked value, stored in place of the value
+ */
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Manifest {
+    // the length of the whole value
+    length: usize,
+    // the hash of the whole value
+    sha256: String,
+    // the hashes of the chunks, in order
+    chunks: Vec<String>,
+}
+
+/*
+    Return the SHA-256 hash of `bytes` as hex
+ */
+fn sha256(bytes: &[u8]) -> String {
+    Sha256::digest(bytes).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn chunk_key(hash: &str) -> Key {
+    format!("{}chunk|{}", CLIENT_METADATA_PREFIX, hash)
+}
+
+/*
+    Split `value` into chunks of at most `chunk_size` bytes, returning the manifest and the chunks
+ */
+fn split(value: &[u8], chunk_size: usize) -> (Manifest, Vec<&[u8]>) {
+    let chunks = value.chunks(chunk_size.max(1)).collect::<Vec<&[u8]>>();
+    let manifest = Manifest {
+        length: value.len(),
+        sha256: sha256(value),
+        chunks: chunks.iter().map(|chunk| sha256(chunk)).collect(),
+    };
+    (manifest, chunks)
+}
+
+fn encode_manifest(manifest: &Manifest) -> Result<Vec<u8>> {
+    let mut bytes = MANIFEST_MAGIC.to_vec();
+    serde_json::to_writer(&mut bytes, manifest).chain_err(|| "Could not encode chunk manifest")?;
+    Ok(bytes)
+}
+
+/*
+    Return the manifest held in the stored `bytes` of a value, if it was chunked
+ */
+fn decode_manifest(bytes: &[u8]) -> Result<Option<Manifest>> {
+    if !bytes.starts_with(MANIFEST_MAGIC) {
+        return Ok(None);
+    }
+    serde_json::from_slice(&bytes[MANIFEST_MAGIC.len()..]).map(Some)
+        .chain_err(|| "Could not decode chunk manifest")
+}
+
+/// Return true if the stored `bytes` of a value are the manifest of a chunked value
+pub fn is_chunked(bytes: &[u8]) -> bool {
+    bytes.starts_with(MANIFEST_MAGIC)
+}
+
+/*
+    Reassemble a value from the `chunks` listed in `manifest`, verifying the hashes
+ */
+fn reassemble(manifest: &Manifest, chunks: &[Vec<u8>]) -> Result<Vec<u8>> {
+    let mut value = Vec::with_capacity(manifest.length);
+    for (hash, chunk) in manifest.chunks.iter().zip(chunks) {
+        if &sha256(chunk) != hash {
+            bail!("Chunk '{}' is corrupt", hash);
+        }
+        value.extend_from_slice(chunk);
+    }
+    if value.len() != manifest.length || sha256(&value) != manifest.sha256 {
+        bail!("Reassembled value does not match its checksum");
+    }
+    Ok(value)
+}
+
+impl KVSClient {
+    /*
+        Store `value` in chunks written with `timestamp` if it is longer than the chunk threshold,
+        returning the manifest to store in its place, or the value itself if it is not
+     */
+    pub(crate) fn chunk_if_large(&mut self, value: Vec<u8>, timestamp: u64) -> Result<Vec<u8>> {
+        if value.len() <= self.get_chunk_threshold() {
+            return Ok(value);
+        }
+
+        let (manifest, chunks) = split(&value, self.get_chunk_threshold());
+        for (hash, chunk) in manifest.chunks.iter().zip(chunks) {
+            self.put_lww_at(&chunk_key(hash), chunk.to_vec(), timestamp)
+                .chain_err(|| format!("Could not store chunk '{}'", hash))?;
+        }
+        encode_manifest(&manifest)
+    }
+
+    /*
+        Replace the stored `bytes` of a value with the reassembled value, if it was chunked
+     */
+    pub(crate) fn reassemble_if_chunked(&mut self, bytes: Vec<u8>) -> Result<Vec<u8>> {
+        let manifest = match decode_manifest(&bytes)? {
+            Some(manifest) => manifest,
+            None => return Ok(bytes),
+        };
+
+        let keys = manifest.chunks.iter().map(|hash| chunk_key(hash)).collect::<Vec<Key>>();
+        let tuples = self.get_lattices(&keys)?;
+        let mut chunks = Vec::with_capacity(keys.len());
+        for key in &keys {
+            let tuple = tuples.get(key).ok_or_else(|| format!("Chunk '{}' is missing", key))?;
+            let lww: LwwValue = decode_tuple(tuple, LatticeType::Lww)?;
+            chunks.push(lww.value);
+        }
+        reassemble(&manifest, &chunks)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode_manifest, encode_manifest, reassemble, split};
+
+    #[test]
+    fn split_and_reassemble() {
+        let value = (0..=255).cycle().take(1000).collect::<Vec<u8>>();
+        let (manifest, chunks) = split(&value, 300);
+        assert_eq!(chunks.len(), 4);
+        let chunks = chunks.iter().map(|chunk| chunk.to_vec()).collect::<Vec<Vec<u8>>>();
+        assert_eq!(reassemble(&manifest, &chunks).expect("Could not reassemble"), value);
+    }
+
+    #[test]
+    fn corrupt_chunks_are_detected() {
+        let (manifest, chunks) = split(b"some large value", 4);
+        let mut chunks = chunks.iter().map(|chunk| chunk.to_vec()).collect::<Vec<Vec<u8>>>();
+        chunks[1][0] ^= 1;
+        assert!(reassemble(&manifest, &chunks).is_err());
+        assert!(reassemble(&manifest, &chunks[..2]).is_err());
+    }
+
+    #[test]
+    fn manifest_round_trip() {
+        let (manifest, _) = split(b"value", 2);
+        let bytes = encode_manifest(&manifest).expect("Could not encode");
+        assert_eq!(decode_manifest(&bytes).expect("Could not decode"), Some(manifest));
+        assert_eq!(decode_manifest(b"plain value").expect("Could not decode"), None);
+    }
+}
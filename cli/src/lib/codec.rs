@@ -0,0 +1,110 @@
+//! Codec module encodes typed Rust values into the bytes stored in `anna` lattices.
+//!
+//! Encoded values start with a small header that records the codec used, so they can be
+//! decoded without the reader needing to know how they were written.
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::errors::*;
+
+// Marks the start of a typed value, followed by one byte with the id of the `Codec` used
+const HEADER_MAGIC: &[u8] = b"\xA7T";
+const HEADER_LENGTH: usize = 3;
+
+/// `Codec` is used to serialize typed values into bytes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Codec {
+    Json,
+    Bincode,
+    MessagePack,
+}
+
+impl Codec {
+    fn id(&self) -> u8 {
+        match self {
+            Codec::Json => 1,
+            Codec::Bincode => 2,
+            Codec::MessagePack => 3,
+        }
+    }
+
+    fn from_id(id: u8) -> Result<Codec> {
+        match id {
+            1 => Ok(Codec::Json),
+            2 => Ok(Codec::Bincode),
+            3 => Ok(Codec::MessagePack),
+            _ => bail!("Unknown codec id {} in value header", id),
+        }
+    }
+}
+
+/// Serialize `value` using `codec`, prefixed with a header recording the codec used
+pub fn encode<T: Serialize>(value: &T, codec: Codec) -> Result<Vec<u8>> {
+    let mut bytes = HEADER_MAGIC.to_vec();
+    bytes.push(codec.id());
+
+    match codec {
+        Codec::Json => serde_json::to_writer(&mut bytes, value)
+            .chain_err(|| "Could not encode value as JSON")?,
+        Codec::Bincode => bincode::serialize_into(&mut bytes, value)
+            .chain_err(|| "Could not encode value using bincode")?,
+        Codec::MessagePack => rmp_serde::encode::write_named(&mut bytes, value)
+            .chain_err(|| "Could not encode value as MessagePack")?,
+    }
+
+    Ok(bytes)
+}
+
+/// Return the `Codec` recorded in the header of `bytes`, or None if they are not a typed value
+pub fn codec_of(bytes: &[u8]) -> Option<Codec> {
+    if bytes.len() < HEADER_LENGTH || !bytes.starts_with(HEADER_MAGIC) {
+        return None;
+    }
+    Codec::from_id(bytes[HEADER_MAGIC.len()]).ok()
+}
+
+/// Deserialize a value of type `T` from `bytes` using the codec recorded in their header
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    if bytes.len() < HEADER_LENGTH || !bytes.starts_with(HEADER_MAGIC) {
+        bail!("Value does not have a typed value header");
+    }
+
+    let body = &bytes[HEADER_LENGTH..];
+    match Codec::from_id(bytes[HEADER_MAGIC.len()])? {
+        Codec::Json => serde_json::from_slice(body)
+            .chain_err(|| "Could not decode JSON value"),
+        Codec::Bincode => bincode::deserialize(body)
+            .chain_err(|| "Could not decode bincode value"),
+        Codec::MessagePack => rmp_serde::from_slice(body)
+            .chain_err(|| "Could not decode MessagePack value"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_derive::{Deserialize, Serialize};
+
+    use super::{Codec, codec_of, decode, encode};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Order {
+        id: u64,
+        items: Vec<String>,
+    }
+
+    #[test]
+    fn round_trip_all_codecs() {
+        let order = Order { id: 42, items: vec!("book".into(), "pen".into()) };
+        for codec in &[Codec::Json, Codec::Bincode, Codec::MessagePack] {
+            let bytes = encode(&order, *codec).expect("Could not encode");
+            assert_eq!(codec_of(&bytes), Some(*codec));
+            assert_eq!(decode::<Order>(&bytes).expect("Could not decode"), order);
+        }
+    }
+
+    #[test]
+    fn untyped_value_is_rejected() {
+        assert_eq!(codec_of(b"plain"), None);
+        assert!(decode::<String>(b"plain").is_err());
+    }
+}
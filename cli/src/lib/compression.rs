@@ -0,0 +1,162 @@
+//! Compression module compresses the LWW values written to `anna`, to reduce the memory they
+//! take up in the storage tiers.
+//!
+//! Compressed values start with a small header that records the algorithm used, so readers
+//! decompress them without needing to know how they were written, and values written without
+//! compression are read as they are.
+use crate::errors::*;
+use crate::kvs_client::KVSClient;
+
+/// Values longer than this, in bytes, are compressed by default if compression is enabled
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+
+// Marks the start of a compressed value, followed by one byte with the id of the
+// `Compression` used
+const HEADER_MAGIC: &[u8] = b"\xA7Z";
+const HEADER_LENGTH: usize = 3;
+
+// The zstd compression level used, zstd's default
+const ZSTD_LEVEL: i32 = 3;
+
+/// `Compression` is the algorithm used to compress values
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    Zstd,
+    Lz4,
+}
+
+impl Compression {
+    /// Return the `Compression` called `name` in the config file and on the command line
+    pub fn from_name(name: &str) -> Result<Compression> {
+        match name {
+            "zstd" => Ok(Compression::Zstd),
+            "lz4" => Ok(Compression::Lz4),
+            _ => bail!("Unknown compression algorithm '{}', expected 'zstd' or 'lz4'", name),
+        }
+    }
+
+    fn id(&self) -> u8 {
+        match self {
+            Compression::Zstd => 1,
+            Compression::Lz4 => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Result<Compression> {
+        match id {
+            1 => Ok(Compression::Zstd),
+            2 => Ok(Compression::Lz4),
+            _ => bail!("Unknown compression id {} in value header", id),
+        }
+    }
+}
+
+/// The lengths of a value written, before and after compression
+#[derive(Debug, PartialEq)]
+pub struct WriteReport {
+    /// The timestamp the value was written with
+    pub timestamp: u64,
+    /// The length of the value, in bytes
+    pub length: usize,
+    /// The length of the value as stored, in bytes
+    pub stored_length: usize,
+}
+
+impl WriteReport {
+    /// Return true if the value was stored compressed
+    pub fn compressed(&self) -> bool {
+        self.stored_length < self.length
+    }
+
+    /// Return the length of the value divided by the length stored
+    pub fn compression_ratio(&self) -> f64 {
+        self.length as f64 / self.stored_length.max(1) as f64
+    }
+}
+
+/// Compress `value` using `compression`, prefixed with a header recording the algorithm used
+pub fn compress(value: &[u8], compression: Compression) -> Result<Vec<u8>> {
+    let mut bytes = HEADER_MAGIC.to_vec();
+    bytes.push(compression.id());
+
+    match compression {
+        Compression::Zstd => bytes.extend(zstd::encode_all(value, ZSTD_LEVEL)
+            .chain_err(|| "Could not compress value using zstd")?),
+        Compression::Lz4 => bytes.extend(lz4_flex::compress_prepend_size(value)),
+    }
+
+    Ok(bytes)
+}
+
+/// Return the `Compression` recorded in the header of `bytes`, or None if they are not compressed
+pub fn compression_of(bytes: &[u8]) -> Option<Compression> {
+    if bytes.len() < HEADER_LENGTH || !bytes.starts_with(HEADER_MAGIC) {
+        return None;
+    }
+    Compression::from_id(bytes[HEADER_MAGIC.len()]).ok()
+}
+
+/// Decompress the stored `bytes` of a value using the algorithm recorded in their header, or
+/// return them as they are if they were not compressed
+pub fn decompress(bytes: Vec<u8>) -> Result<Vec<u8>> {
+    if bytes.len() < HEADER_LENGTH || !bytes.starts_with(HEADER_MAGIC) {
+        return Ok(bytes);
+    }
+
+    let body = &bytes[HEADER_LENGTH..];
+    match Compression::from_id(bytes[HEADER_MAGIC.len()])? {
+        Compression::Zstd => zstd::decode_all(body)
+            .chain_err(|| "Could not decompress zstd value"),
+        Compression::Lz4 => lz4_flex::decompress_size_prepended(body)
+            .chain_err(|| "Could not decompress lz4 value"),
+    }
+}
+
+impl KVSClient {
+    /*
+        Compress `value` if compression is enabled and it is longer than the compression
+        threshold, returning the value to store. Values that do not get smaller are stored
+        as they are.
+     */
+    pub(crate) fn compress_if_large(&self, value: Vec<u8>) -> Result<Vec<u8>> {
+        let compression = match self.get_compression() {
+            Some(compression) if value.len() > self.get_compression_threshold() => compression,
+            _ => return Ok(value),
+        };
+
+        let compressed = compress(&value, compression)?;
+        if compressed.len() < value.len() {
+            Ok(compressed)
+        } else {
+            Ok(value)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Compression, compress, compression_of, decompress};
+
+    #[test]
+    fn round_trip_all_algorithms() {
+        let value = br#"{"name": "anna", "tags": ["kvs", "kvs", "kvs", "kvs", "kvs"]}"#.repeat(20);
+        for compression in &[Compression::Zstd, Compression::Lz4] {
+            let bytes = compress(&value, *compression).expect("Could not compress");
+            assert_eq!(compression_of(&bytes), Some(*compression));
+            assert!(bytes.len() < value.len());
+            assert_eq!(decompress(bytes).expect("Could not decompress"), value);
+        }
+    }
+
+    #[test]
+    fn uncompressed_values_are_read_as_they_are() {
+        assert_eq!(compression_of(b"plain"), None);
+        assert_eq!(decompress(b"plain".to_vec()).expect("Could not decompress"), b"plain");
+    }
+
+    #[test]
+    fn algorithm_names() {
+        assert_eq!(Compression::from_name("lz4").expect("lz4"), Compression::Lz4);
+        assert!(Compression::from_name("gzip").is_err());
+    }
+}
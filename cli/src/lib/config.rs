@@ -20,6 +20,17 @@ pub struct Config {
     capacities: Capacities,
     threads: Threads,
     replication: Replication,
+    #[serde(default)]
+    renderers: Vec<RendererEntry>,
+    #[serde(default)]
+    indexes: Vec<IndexEntry>,
+    #[serde(default)]
+    catalog: bool,
+    compression: Option<CompressionEntry>,
+    keyring: Option<String>,
+    journal: Option<String>,
+    #[serde(default)]
+    versioned: Vec<String>,
 }
 
 /// Monitoring configuration section
@@ -96,6 +107,30 @@ struct Replication {
     local: usize,
 }
 
+/// Renderers configuration section entry, selecting a built-in renderer for matching keys
+#[derive(Deserialize)]
+struct RendererEntry {
+    pattern: String,
+    renderer: String,
+}
+
+/// Indexes configuration section entry, declaring a secondary index on the keys with `prefix`
+/// over the value of `field` (a JSON pointer) in their JSON or typed values
+#[derive(Deserialize)]
+struct IndexEntry {
+    name: String,
+    prefix: String,
+    field: String,
+}
+
+/// Compression configuration section, compressing LWW values longer than `threshold` bytes
+/// using `algorithm` ("zstd" or "lz4")
+#[derive(Deserialize)]
+struct CompressionEntry {
+    algorithm: String,
+    threshold: Option<usize>,
+}
+
 /// `Config` Contains the Anna configuration deserialized form Yaml config file
 impl Config {
     /// Read the `Config` from a yaml config file and return it or Error
@@ -126,11 +161,53 @@ impl Config {
     pub fn get_routing_thread_count(&self) -> usize {
         self.threads.routing
     }
+
+    /// Return the (key pattern, renderer name) pairs configured, in order
+    pub fn get_renderers(&self) -> Vec<(&str, &str)> {
+        self.renderers.iter()
+            .map(|entry| (entry.pattern.as_str(), entry.renderer.as_str()))
+            .collect()
+    }
+
+    /// Return true if the keys written should be registered in the key catalog
+    pub fn catalog_enabled(&self) -> bool {
+        self.catalog
+    }
+
+    /// Return the (algorithm, threshold) LWW values are compressed with, if compression is enabled
+    pub fn get_compression(&self) -> Option<(&str, Option<usize>)> {
+        self.compression.as_ref()
+            .map(|entry| (entry.algorithm.as_str(), entry.threshold))
+    }
+
+    /// Return the name of the keyring file values are encrypted with, if encryption is enabled
+    pub fn get_keyring_file(&self) -> Option<&str> {
+        self.keyring.as_deref()
+    }
+
+    /// Return the name of the file writes are journaled in while the cluster is unreachable,
+    /// if journaling is enabled
+    pub fn get_journal_file(&self) -> Option<&str> {
+        self.journal.as_deref()
+    }
+
+    /// Return the patterns of the keys whose history of values is kept
+    pub fn get_versioned_patterns(&self) -> &[String] {
+        &self.versioned
+    }
+
+    /// Return the (index name, key prefix, field) of the secondary indexes configured
+    pub fn get_indexes(&self) -> Vec<(&str, &str, &str)> {
+        self.indexes.iter()
+            .map(|entry| (entry.name.as_str(), entry.prefix.as_str(), entry.field.as_str()))
+            .collect()
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::Config;
+    use crate::encryption::Keyring;
 
     #[test]
     fn routing_ips_no_elb() {
@@ -152,4 +229,48 @@ mod test {
             .expect("Could not read the 'test_config.yml' config file");
         assert_eq!(config.get_routing_thread_count(), 1);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn renderers() {
+        let config = Config::read("src/lib/test_config.yml")
+            .expect("Could not read the 'test_config.yml' config file");
+        assert_eq!(config.get_renderers(), vec!(("json/*", "json")));
+    }
+
+    #[test]
+    fn indexes() {
+        let config = Config::read("src/lib/test_config.yml")
+            .expect("Could not read the 'test_config.yml' config file");
+        assert_eq!(config.get_indexes(), vec!(("users_by_email", "users/", "/email")));
+    }
+
+    #[test]
+    fn catalog() {
+        let config = Config::read("src/lib/test_config.yml")
+            .expect("Could not read the 'test_config.yml' config file");
+        assert!(config.catalog_enabled());
+    }
+
+    #[test]
+    fn compression() {
+        let config = Config::read("src/lib/test_config.yml")
+            .expect("Could not read the 'test_config.yml' config file");
+        assert_eq!(config.get_compression(), Some(("zstd", Some(4096))));
+    }
+
+    #[test]
+    fn keyring() {
+        let config = Config::read("src/lib/test_config.yml")
+            .expect("Could not read the 'test_config.yml' config file");
+        let filename = config.get_keyring_file().expect("No keyring file configured");
+        let keyring = Keyring::read(filename).expect("Could not read the keyring file");
+        assert_eq!(keyring.active_key_id(), "2020-02");
+    }
+
+    #[test]
+    fn versioned() {
+        let config = Config::read("src/lib/test_config.yml")
+            .expect("Could not read the 'test_config.yml' config file");
+        assert_eq!(config.get_versioned_patterns(), &["config/*".to_string()]);
+    }
+}
@@ -0,0 +1,7 @@
+//! CRDT module provides replicated data types that `anna` does not support natively, built
+//! from the lattices it does support and computed client-side.
+mod counter;
+mod or_set;
+
+pub use counter::{Counter, CounterKind};
+pub use or_set::ORSet;
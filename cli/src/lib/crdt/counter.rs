@@ -0,0 +1,303 @@
+//! Counters that can be incremented (and decremented) without coordination.
+//!
+//! Each increment or decrement is stored as a unique token (the amount, plus the id of the
+//! client and a sequence number) in a SET lattice "segment" key, so concurrent updates merge
+//! by set union and none are lost. The value of the counter is the sum of all the tokens.
+//!
+//! To stop segments growing forever the counter can be compacted, which sums the current
+//! segment into an LWW checkpoint key and moves writers on to a new segment. Tokens written to
+//! the previous segment after compaction are still counted, and are absorbed by the next
+//! compaction, but writers must not fall more than one compaction behind.
+//!
+//! The kind of a counter is recorded in a SET lattice "kind" key by its first write, so a
+//! grow-only counter cannot be decremented through a handle created as positive-negative. If
+//! first writes of both kinds are concurrent, the counter is grow-only from then on.
+use std::convert::TryFrom;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::codec::Codec;
+use crate::errors::*;
+use crate::kvs_client::{default_if_missing, KVSClient, Key};
+
+/// The kind of a `Counter`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CounterKind {
+    /// A counter that can only be incremented
+    GrowOnly,
+    /// A counter that can be incremented and decremented
+    PositiveNegative,
+}
+
+impl CounterKind {
+    fn name(self) -> &'static [u8] {
+        match self {
+            CounterKind::GrowOnly => b"grow_only",
+            CounterKind::PositiveNegative => b"positive_negative",
+        }
+    }
+
+    /*
+        The kind recorded by the `names` in the kind key of a counter, grow-only if any are
+     */
+    fn recorded(names: &[Vec<u8>]) -> Option<CounterKind> {
+        if names.iter().any(|name| name == CounterKind::GrowOnly.name()) {
+            Some(CounterKind::GrowOnly)
+        } else if names.is_empty() {
+            None
+        } else {
+            Some(CounterKind::PositiveNegative)
+        }
+    }
+}
+
+/// `Counter` is a counter stored in `anna` under `key`
+pub struct Counter {
+    key: Key,
+    kind: CounterKind,
+}
+
+/*
+    The state of a compacted counter, stored in the checkpoint key
+ */
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+struct Checkpoint {
+    // the segment currently written to
+    epoch: u64,
+    // the sum of all the tokens in the segments before the previous one, and those absorbed
+    // from the previous segment
+    base: i64,
+    // the ids of the tokens in the previous segment included in `base`
+    absorbed: Vec<String>,
+}
+
+/*
+    One increment or decrement of a counter
+ */
+#[derive(Debug, PartialEq)]
+struct Token {
+    delta: i64,
+    id: String,
+}
+
+impl Token {
+    fn encode(&self) -> Vec<u8> {
+        format!("{:+}@{}", self.delta, self.id).into_bytes()
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Token> {
+        let text = String::from_utf8_lossy(bytes);
+        let (delta, id) = text.split_once('@')
+            .ok_or_else(|| format!("'{}' is not a counter token", text))?;
+        Ok(Token {
+            delta: delta.parse().chain_err(|| format!("'{}' is not a counter token", text))?,
+            id: id.into(),
+        })
+    }
+}
+
+impl Counter {
+    /// Create a grow-only counter stored under `key`
+    pub fn grow_only(key: &str) -> Self {
+        Counter { key: key.into(), kind: CounterKind::GrowOnly }
+    }
+
+    /// Create a positive-negative counter stored under `key`
+    pub fn pn(key: &str) -> Self {
+        Counter { key: key.into(), kind: CounterKind::PositiveNegative }
+    }
+
+    /// Return the kind of this counter
+    pub fn kind(&self) -> CounterKind {
+        self.kind
+    }
+
+    /// Add `amount` to the counter
+    pub fn increment(&self, client: &mut KVSClient, amount: u64) -> Result<()> {
+        self.add(client, delta(amount)?)
+    }
+
+    /// Subtract `amount` from the counter. Grow-only counters cannot be decremented, whatever
+    /// the kind of this handle.
+    pub fn decrement(&self, client: &mut KVSClient, amount: u64) -> Result<()> {
+        if self.kind == CounterKind::GrowOnly {
+            bail!("Counter '{}' is grow-only and cannot be decremented", self.key);
+        }
+        self.add(client, -delta(amount)?)
+    }
+
+    /// Return the kind recorded for the counter by its first write, if it has been written
+    pub fn recorded_kind(&self, client: &mut KVSClient) -> Result<Option<CounterKind>> {
+        let names = default_if_missing(client.get_set_values(&self.kind_key()))?;
+        Ok(CounterKind::recorded(&names))
+    }
+
+    /// Read the current value of the counter
+    pub fn value(&self, client: &mut KVSClient) -> Result<i64> {
+        let checkpoint = self.checkpoint(client)?;
+        let current = self.tokens(client, checkpoint.epoch)?;
+        let previous = self.previous_tokens(client, &checkpoint)?;
+        total(&checkpoint, &current, &previous)
+    }
+
+    /// Sum the tokens of the current segment into the checkpoint, and move writers on to a new
+    /// segment
+    pub fn compact(&self, client: &mut KVSClient) -> Result<()> {
+        let checkpoint = self.checkpoint(client)?;
+        let current = self.tokens(client, checkpoint.epoch)?;
+        let previous = self.previous_tokens(client, &checkpoint)?;
+
+        let compacted = Checkpoint {
+            epoch: checkpoint.epoch + 1,
+            base: total(&checkpoint, &current, &previous)?,
+            absorbed: current.into_iter().map(|token| token.id).collect(),
+        };
+        client.put_typed(&self.checkpoint_key(), &compacted, Codec::Json)?;
+        Ok(())
+    }
+
+    fn add(&self, client: &mut KVSClient, delta: i64) -> Result<()> {
+        match self.recorded_kind(client)? {
+            Some(CounterKind::GrowOnly) if delta < 0 =>
+                bail!("Counter '{}' is grow-only and cannot be decremented", self.key),
+            Some(_) => {}
+            None => client.add_to_set(&self.kind_key(), vec!(self.kind.name().to_vec()))?,
+        }
+        let epoch = self.checkpoint(client)?.epoch;
+        let token = Token { delta, id: client.generate_unique_id() };
+        client.add_to_set(&self.segment_key(epoch), vec!(token.encode()))?;
+        client.register_key(&self.key)
+    }
+
+    fn checkpoint(&self, client: &mut KVSClient) -> Result<Checkpoint> {
+        default_if_missing(client.get_typed(&self.checkpoint_key()))
+    }
+
+    fn tokens(&self, client: &mut KVSClient, epoch: u64) -> Result<Vec<Token>> {
+        default_if_missing(client.get_set_values(&self.segment_key(epoch)))?
+            .iter().map(|bytes| Token::decode(bytes)).collect()
+    }
+
+    fn previous_tokens(&self, client: &mut KVSClient, checkpoint: &Checkpoint) -> Result<Vec<Token>> {
+        match checkpoint.epoch {
+            0 => Ok(Vec::new()),
+            epoch => self.tokens(client, epoch - 1),
+        }
+    }
+
+    fn checkpoint_key(&self) -> Key {
+        format!("{}/checkpoint", self.key)
+    }
+
+    fn kind_key(&self) -> Key {
+        format!("{}/kind", self.key)
+    }
+
+    fn segment_key(&self, epoch: u64) -> Key {
+        format!("{}/{}", self.key, epoch)
+    }
+}
+
+/*
+    The delta of a token adding `amount`, which must fit in an i64
+ */
+fn delta(amount: u64) -> Result<i64> {
+    i64::try_from(amount).chain_err(|| format!("Amount {} is too large for a counter", amount))
+}
+
+/*
+    The value of a counter with `checkpoint`, the tokens of the `current` segment and of the
+    `previous` one (those already absorbed into the checkpoint are ignored)
+ */
+fn total(checkpoint: &Checkpoint, current: &[Token], previous: &[Token]) -> Result<i64> {
+    previous.iter()
+        .filter(|token| !checkpoint.absorbed.contains(&token.id))
+        .chain(current)
+        .try_fold(checkpoint.base, |total, token| total.checked_add(token.delta))
+        .ok_or_else(|| "The value of the counter overflows an i64".into())
+}
+
+/*
+    Parse the optional amount of an INCR or DECR command
+ */
+fn amount(tokens: &[&str]) -> Result<u64> {
+    match tokens.get(1) {
+        Some(amount) => amount.parse().chain_err(|| format!("'{}' is not a valid amount", amount)),
+        None => Ok(1),
+    }
+}
+
+impl KVSClient {
+    /*
+        INCR <key> [<amount>]
+     */
+    pub fn incr(&mut self, tokens: &[&str]) -> Result<String> {
+        let key = tokens.first().ok_or("Usage: INCR <key> [<amount>]")?;
+        Counter::pn(key).increment(self, amount(tokens)?)?;
+        Ok("Success!".into())
+    }
+
+    /*
+        DECR <key> [<amount>]
+     */
+    pub fn decr(&mut self, tokens: &[&str]) -> Result<String> {
+        let key = tokens.first().ok_or("Usage: DECR <key> [<amount>]")?;
+        Counter::pn(key).decrement(self, amount(tokens)?)?;
+        Ok("Success!".into())
+    }
+
+    /*
+        COUNT <key>
+     */
+    pub fn count(&mut self, tokens: &[&str]) -> Result<String> {
+        let key = tokens.first().ok_or("Usage: COUNT <key>")?;
+        Ok(Counter::pn(key).value(self)?.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{delta, Checkpoint, CounterKind, Token, total};
+
+    fn token(delta: i64, id: &str) -> Token {
+        Token { delta, id: id.into() }
+    }
+
+    #[test]
+    fn token_round_trip() {
+        let decrement = token(-3, "127.0.0.1:0_42:1600000000000:7");
+        assert_eq!(Token::decode(&decrement.encode()).expect("Could not decode"), decrement);
+        assert!(Token::decode(b"garbage").is_err());
+    }
+
+    #[test]
+    fn total_without_checkpoint() {
+        let current = vec!(token(5, "a"), token(-2, "b"));
+        assert_eq!(total(&Checkpoint::default(), &current, &[]).expect("total"), 3);
+    }
+
+    #[test]
+    fn late_tokens_are_counted_once() {
+        let checkpoint = Checkpoint { epoch: 1, base: 10, absorbed: vec!("a".into()) };
+        let previous = vec!(token(10, "a"), token(4, "late"));
+        let current = vec!(token(1, "c"));
+        assert_eq!(total(&checkpoint, &current, &previous).expect("total"), 15);
+    }
+
+    #[test]
+    fn grow_only_is_recorded_over_positive_negative() {
+        let pn = vec!(CounterKind::PositiveNegative.name().to_vec());
+        let both = vec!(pn[0].clone(), CounterKind::GrowOnly.name().to_vec());
+        assert_eq!(CounterKind::recorded(&[]), None);
+        assert_eq!(CounterKind::recorded(&pn), Some(CounterKind::PositiveNegative));
+        assert_eq!(CounterKind::recorded(&both), Some(CounterKind::GrowOnly));
+    }
+
+    #[test]
+    fn overflows_are_errors() {
+        assert!(delta(u64::MAX).is_err());
+        assert_eq!(delta(i64::MAX as u64).expect("delta"), i64::MAX);
+        let current = vec!(token(i64::MAX, "a"), token(1, "b"));
+        assert!(total(&Checkpoint::default(), &current, &[]).is_err());
+    }
+}
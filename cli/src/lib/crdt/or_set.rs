@@ -0,0 +1,233 @@
+//! An observed-remove set, which supports removing members from a set without coordination.
+//!
+//! Each add tags the member with a unique id and stores the tagged member in an "adds" SET
+//! lattice key. A remove stores every tag it has observed for the member in a "removes" SET
+//! lattice key, and a member is in the set while it has a tag that has not been removed. So an
+//! add that is concurrent with a remove wins, as the remove cannot have observed its tag.
+//!
+//! As both keys only grow, the set can be compacted: the tags still live are copied into the
+//! adds key of a new epoch, and writers move on to it. Reads include the previous epoch, so
+//! writes made to it after compaction are not lost and are absorbed by the next compaction,
+//! but writers must not fall more than one compaction behind.
+use std::collections::{BTreeSet, HashSet};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::codec::Codec;
+use crate::display::format_set;
+use crate::errors::*;
+use crate::kvs_client::{default_if_missing, KVSClient, Key};
+
+/// `ORSet` is an observed-remove set of strings stored in `anna` under `key`
+pub struct ORSet {
+    key: Key,
+}
+
+/*
+    The epoch of a compacted set, stored in the epoch key
+ */
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+struct Epoch {
+    epoch: u64,
+}
+
+/*
+    The tagged members and tombstones of the epochs being read
+ */
+#[derive(Debug, Default)]
+struct State {
+    adds: HashSet<Vec<u8>>,
+    removes: HashSet<Vec<u8>>,
+}
+
+/*
+    Tag `member` with the unique `id`. Ids never contain '@', so the tag is split at the first one.
+ */
+fn tag(member: &str, id: &str) -> Vec<u8> {
+    format!("{}@{}", id, member).into_bytes()
+}
+
+/*
+    Return the member of a tagged member
+ */
+fn member_of(tagged: &[u8]) -> Result<String> {
+    let text = String::from_utf8_lossy(tagged);
+    match text.split_once('@') {
+        Some((_, member)) => Ok(member.into()),
+        None => bail!("'{}' is not a tagged set member", text),
+    }
+}
+
+impl State {
+    /*
+        Return the tagged members that have not been removed
+     */
+    fn live(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.adds.iter().filter(move |tagged| !self.removes.contains(*tagged))
+    }
+
+    fn members(&self) -> Result<BTreeSet<String>> {
+        self.live().map(|tagged| member_of(tagged)).collect()
+    }
+
+    /*
+        Return the live tags of `member`
+     */
+    fn tags_of(&self, member: &str) -> Result<Vec<Vec<u8>>> {
+        let mut tags = Vec::new();
+        for tagged in self.live() {
+            if member_of(tagged)? == member {
+                tags.push(tagged.clone());
+            }
+        }
+        Ok(tags)
+    }
+}
+
+impl ORSet {
+    /// Create an observed-remove set stored under `key`
+    pub fn new(key: &str) -> Self {
+        ORSet { key: key.into() }
+    }
+
+    /// Add `member` to the set
+    pub fn add(&self, client: &mut KVSClient, member: &str) -> Result<()> {
+        let epoch = self.epoch(client)?;
+        let tagged = tag(member, &client.generate_unique_id());
+        client.add_to_set(&self.adds_key(epoch), vec!(tagged))?;
+        client.register_key(&self.key)
+    }
+
+    /// Remove `member` from the set. Returns false if it was not a member.
+    pub fn remove(&self, client: &mut KVSClient, member: &str) -> Result<bool> {
+        let epoch = self.epoch(client)?;
+        let tags = self.state(client, epoch)?.tags_of(member)?;
+        if tags.is_empty() {
+            return Ok(false);
+        }
+        client.add_to_set(&self.removes_key(epoch), tags)?;
+        Ok(true)
+    }
+
+    /// Return the members of the set, in order
+    pub fn members(&self, client: &mut KVSClient) -> Result<BTreeSet<String>> {
+        let epoch = self.epoch(client)?;
+        self.state(client, epoch)?.members()
+    }
+
+    /// Copy the live tagged members into a new epoch, so that tombstones (and the members they
+    /// removed) are no longer read. The keys of older epochs are left in `anna`.
+    pub fn compact(&self, client: &mut KVSClient) -> Result<()> {
+        let epoch = self.epoch(client)?;
+        let live = self.state(client, epoch)?.live().cloned().collect::<Vec<Vec<u8>>>();
+        if !live.is_empty() {
+            client.add_to_set(&self.adds_key(epoch + 1), live)?;
+        }
+        client.put_typed(&self.epoch_key(), &Epoch { epoch: epoch + 1 }, Codec::Json)?;
+        Ok(())
+    }
+
+    fn epoch(&self, client: &mut KVSClient) -> Result<u64> {
+        Ok(default_if_missing(client.get_typed::<Epoch>(&self.epoch_key()))?.epoch)
+    }
+
+    /*
+        Read the adds and removes of `epoch` and the epoch before it
+     */
+    fn state(&self, client: &mut KVSClient, epoch: u64) -> Result<State> {
+        let mut state = State::default();
+        for epoch in epoch.saturating_sub(1)..=epoch {
+            state.adds.extend(default_if_missing(client.get_set_values(&self.adds_key(epoch)))?);
+            state.removes.extend(default_if_missing(client.get_set_values(&self.removes_key(epoch)))?);
+        }
+        Ok(state)
+    }
+
+    fn epoch_key(&self) -> Key {
+        format!("{}/epoch", self.key)
+    }
+
+    fn adds_key(&self, epoch: u64) -> Key {
+        format!("{}/{}/adds", self.key, epoch)
+    }
+
+    fn removes_key(&self, epoch: u64) -> Key {
+        format!("{}/{}/removes", self.key, epoch)
+    }
+}
+
+impl KVSClient {
+    /*
+        SADD <key> <member>...
+     */
+    pub fn sadd(&mut self, tokens: &[&str]) -> Result<String> {
+        if tokens.len() < 2 {
+            bail!("Usage: SADD <key> <member>...");
+        }
+        let set = ORSet::new(tokens[0]);
+        for member in &tokens[1..] {
+            set.add(self, member)?;
+        }
+        Ok("Success!".into())
+    }
+
+    /*
+        SREM <key> <member>...
+     */
+    pub fn srem(&mut self, tokens: &[&str]) -> Result<String> {
+        if tokens.len() < 2 {
+            bail!("Usage: SREM <key> <member>...");
+        }
+        let set = ORSet::new(tokens[0]);
+        let mut removed = 0;
+        for member in &tokens[1..] {
+            if set.remove(self, member)? {
+                removed += 1;
+            }
+        }
+        Ok(format!("Removed {} member(s)", removed))
+    }
+
+    /*
+        SMEMBERS <key>
+     */
+    pub fn smembers(&mut self, tokens: &[&str]) -> Result<String> {
+        let key = tokens.first().ok_or("Usage: SMEMBERS <key>")?;
+        let members = ORSet::new(key).members(self)?;
+        Ok(format_set(&members.into_iter().collect::<Vec<String>>()))
+    }
+
+    /*
+        SCOMPACT <key>
+     */
+    pub fn scompact(&mut self, tokens: &[&str]) -> Result<String> {
+        let key = tokens.first().ok_or("Usage: SCOMPACT <key>")?;
+        ORSet::new(key).compact(self)?;
+        Ok("Success!".into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{member_of, tag, State};
+
+    #[test]
+    fn members_may_contain_the_separator() {
+        assert_eq!(member_of(&tag("user@example.com", "client:1:2")).expect("tagged"),
+                   "user@example.com");
+        assert!(member_of(b"untagged").is_err());
+    }
+
+    #[test]
+    fn concurrent_add_wins_over_remove() {
+        let mut state = State::default();
+        state.adds.insert(tag("alice", "1"));
+        state.adds.insert(tag("bob", "2"));
+        // a remove of alice that has observed tag 1, concurrent with a re-add under tag 3
+        state.removes.extend(state.tags_of("alice").expect("tags"));
+        assert_eq!(state.members().expect("members").into_iter().collect::<Vec<String>>(),
+                   vec!("bob"));
+        state.adds.insert(tag("alice", "3"));
+        assert_eq!(state.members().expect("members").len(), 2);
+    }
+}
@@ -0,0 +1,199 @@
+//! Display module formats the lattices retrieved from `anna` for output by the CLI, whatever
+//! the type of lattice stored in the key
+use std::collections::HashMap;
+
+use crate::errors::*;
+use crate::kvs_client::decode_tuple;
+use crate::proto::anna::{KeyTuple, LatticeType, LwwValue, MultiKeyCausalValue, PriorityValue,
+                         SetValue, SingleKeyCausalValue};
+use crate::envelope::strip_plain;
+use crate::expiry::split_expiry;
+use crate::ramp::decode_record;
+use crate::renderers::Renderer;
+use crate::tombstone::is_tombstone;
+
+/// Return the name used for `lattice_type` in `anna.proto`
+pub fn lattice_type_name(lattice_type: LatticeType) -> &'static str {
+    match lattice_type {
+        LatticeType::None => "NONE",
+        LatticeType::Lww => "LWW",
+        LatticeType::Set => "SET",
+        LatticeType::SingleCausal => "SINGLE_CAUSAL",
+        LatticeType::MultiCausal => "MULTI_CAUSAL",
+        LatticeType::OrderedSet => "ORDERED_SET",
+        LatticeType::Priority => "PRIORITY",
+    }
+}
+
+/// Format the value held in `tuple` using `renderer`, dispatching on the type of lattice it
+/// holds. If `metadata` is true then the lattice type, timestamp, vector clocks, dependencies
+/// or priority are included, one per line.
+pub fn format_tuple(tuple: &KeyTuple, renderer: &dyn Renderer, metadata: bool) -> Result<String> {
+    let mut lines = Vec::new();
+    if metadata {
+        lines.push(format!("type: {}", lattice_type_name(tuple.lattice_type())));
+    }
+
+    let value = match tuple.lattice_type() {
+        LatticeType::Lww => {
+            let lww: LwwValue = decode_tuple(tuple, LatticeType::Lww)?;
+            if metadata {
+                lines.push(format!("timestamp: {}", lww.timestamp));
+            }
+            let (expires_at, value) = split_expiry(&lww.value);
+            if let (true, Some(expires_at)) = (metadata, expires_at) {
+                lines.push(format!("expires: {}", expires_at));
+            }
+            let record = decode_record(value)?;
+            let value = match &record {
+                Some(record) => {
+                    if metadata {
+                        lines.push(format!("transaction: {} (siblings: {})", record.version,
+                                           record.siblings.join(", ")));
+                    }
+                    &record.value
+                }
+                None => value,
+            };
+            if is_tombstone(value) {
+                "(deleted)".into()
+            } else {
+                renderer.render(strip_plain(value))?
+            }
+        }
+        LatticeType::Set => {
+            let set: SetValue = decode_tuple(tuple, LatticeType::Set)?;
+            format_set(&render_all(&set.values, renderer)?)
+        }
+        LatticeType::OrderedSet => {
+            let set: SetValue = decode_tuple(tuple, LatticeType::OrderedSet)?;
+            let mut values = set.values;
+            values.sort();
+            values.dedup();
+            format_ordered_set(&render_all(&values, renderer)?)
+        }
+        LatticeType::SingleCausal => {
+            let causal: SingleKeyCausalValue = decode_tuple(tuple, LatticeType::SingleCausal)?;
+            if metadata {
+                lines.push(format!("vector clock: {}", format_vector_clock(&causal.vector_clock)));
+            }
+            format_set(&render_all(&causal.values, renderer)?)
+        }
+        LatticeType::MultiCausal => {
+            let causal: MultiKeyCausalValue = decode_tuple(tuple, LatticeType::MultiCausal)?;
+            if metadata {
+                lines.push(format!("vector clock: {}", format_vector_clock(&causal.vector_clock)));
+                lines.push("dependencies:".into());
+                for dependency in &causal.dependencies {
+                    lines.push(format!("  {} : {}", dependency.key,
+                                       format_vector_clock(&dependency.vector_clock)));
+                }
+            }
+            format_set(&render_all(&causal.values, renderer)?)
+        }
+        LatticeType::Priority => {
+            let priority: PriorityValue = decode_tuple(tuple, LatticeType::Priority)?;
+            if metadata {
+                lines.push(format!("priority: {}", priority.priority));
+            }
+            renderer.render(&priority.value)?
+        }
+        LatticeType::None => bail!("Key '{}' has no lattice type", tuple.key),
+    };
+
+    if metadata {
+        lines.push(format!("value: {}", value));
+    } else {
+        lines.push(value);
+    }
+
+    Ok(lines.join("\n"))
+}
+
+/*
+    Render each of `values` using `renderer`
+ */
+fn render_all(values: &[Vec<u8>], renderer: &dyn Renderer) -> Result<Vec<String>> {
+    values.iter().map(|value| renderer.render(value)).collect()
+}
+
+/// Format the members of a set in the same way as the original C++ CLI, sorted so that the
+/// output is stable
+pub fn format_set(values: &[String]) -> String {
+    let mut values = values.to_vec();
+    values.sort();
+    format_ordered_set(&values)
+}
+
+/// Format the members of a set, in the order given
+pub fn format_ordered_set(values: &[String]) -> String {
+    format!("{{ {}}}", values.iter().map(|value| format!("{} ", value)).collect::<String>())
+}
+
+/// Format a vector clock, with the client ids in order
+pub fn format_vector_clock(vector_clock: &HashMap<String, u32>) -> String {
+    let mut entries = vector_clock.iter().collect::<Vec<(&String, &u32)>>();
+    entries.sort();
+    entries.iter().map(|(id, version)| format!("{{{} : {}}}", id, version))
+        .collect::<Vec<String>>().join(" ")
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::{format_set, format_tuple, format_vector_clock};
+    use crate::kvs_client::encode;
+    use crate::proto::anna::{KeyTuple, LatticeType, LwwValue, PriorityValue, SetValue};
+    use crate::renderers::RawRenderer;
+
+    fn tuple(lattice_type: LatticeType, payload: Vec<u8>) -> KeyTuple {
+        KeyTuple {
+            key: "key".into(),
+            lattice_type: lattice_type as i32,
+            payload,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn set_format() {
+        assert_eq!(format_set(&["b".into(), "a".into()]), "{ a b }");
+    }
+
+    #[test]
+    fn vector_clock_format() {
+        let mut clock = HashMap::new();
+        clock.insert("b".to_string(), 2);
+        clock.insert("a".to_string(), 1);
+        assert_eq!(format_vector_clock(&clock), "{a : 1} {b : 2}");
+    }
+
+    #[test]
+    fn lww_value_and_metadata() {
+        let lww = tuple(LatticeType::Lww, encode(&LwwValue { timestamp: 42, value: b"v".to_vec() }));
+        assert_eq!(format_tuple(&lww, &RawRenderer, false).expect("LWW"), "v");
+        assert_eq!(format_tuple(&lww, &RawRenderer, true).expect("LWW"),
+                   "type: LWW\ntimestamp: 42\nvalue: v");
+    }
+
+    #[test]
+    fn ordered_set_value() {
+        let set = tuple(LatticeType::OrderedSet,
+                        encode(&SetValue { values: vec!(b"2".to_vec(), b"1".to_vec()) }));
+        assert_eq!(format_tuple(&set, &RawRenderer, false).expect("ORDERED_SET"), "{ 1 2 }");
+    }
+
+    #[test]
+    fn priority_metadata() {
+        let priority = tuple(LatticeType::Priority,
+                             encode(&PriorityValue { priority: 1.5, value: b"cheap".to_vec() }));
+        assert_eq!(format_tuple(&priority, &RawRenderer, true).expect("PRIORITY"),
+                   "type: PRIORITY\npriority: 1.5\nvalue: cheap");
+    }
+
+    #[test]
+    fn no_lattice_type_is_an_error() {
+        assert!(format_tuple(&tuple(LatticeType::None, vec!()), &RawRenderer, false).is_err());
+    }
+}
@@ -0,0 +1,144 @@
+//! Document module stores documents (hashes of fields) so that concurrent updates to different
+//! fields merge, rather than one clobbering the other as they would if the whole document was
+//! written to one LWW lattice.
+//!
+//! Each field of a document is stored in its own LWW lattice key, under the key of the document,
+//! and the key of the document holds a SET lattice of the names of its fields.
+use std::collections::{BTreeMap, BTreeSet};
+
+use log::debug;
+
+use crate::errors::*;
+use crate::kvs_client::{default_if_missing, KVSClient, Key};
+
+/// `Document` is a document stored in `anna` under `key`
+pub struct Document {
+    key: Key,
+}
+
+impl Document {
+    /// Create a document stored under `key`
+    pub fn new(key: &str) -> Self {
+        Document { key: key.into() }
+    }
+
+    /// Return the key that `field` of the document is stored in
+    pub fn field_key(&self, field: &str) -> Key {
+        format!("{}/{}", self.key, field)
+    }
+
+    /// Set `field` of the document to `value`, returning the timestamp it was written with
+    pub fn set(&self, client: &mut KVSClient, field: &str, value: Vec<u8>) -> Result<u64> {
+        let timestamp = client.put_lww(&self.field_key(field), value)?;
+        client.add_to_set(&self.key, vec!(field.as_bytes().to_vec()))?;
+        client.register_key(&self.key)?;
+        Ok(timestamp)
+    }
+
+    /// Get the value of `field` of the document
+    pub fn get(&self, client: &mut KVSClient, field: &str) -> Result<Vec<u8>> {
+        Ok(client.get_lww(&self.field_key(field))?.value)
+    }
+
+    /// Return the names of the fields that have been set in the document
+    pub fn fields(&self, client: &mut KVSClient) -> Result<BTreeSet<String>> {
+        Ok(default_if_missing(client.get_set_values(&self.key))?.iter()
+            .map(|field| String::from_utf8_lossy(field).to_string())
+            .collect())
+    }
+
+    /// Get all the fields of the document. Fields that have been deleted are skipped.
+    pub fn get_all(&self, client: &mut KVSClient) -> Result<BTreeMap<String, Vec<u8>>> {
+        let mut document = BTreeMap::new();
+        for field in self.fields(client)? {
+            match self.get(client, &field) {
+                Ok(value) => {
+                    document.insert(field, value);
+                }
+                Err(Error(ErrorKind::KeyDoesNotExist(_), _)) => debug!("Field '{}' was deleted", field),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(document)
+    }
+
+    /// Set each member of the JSON `object` as a field of the document, with the member's
+    /// value stored as JSON
+    pub fn set_json(&self, client: &mut KVSClient, object: &serde_json::Value) -> Result<()> {
+        let members = object.as_object()
+            .ok_or_else(|| format!("Only JSON objects can be stored in document '{}'", self.key))?;
+        for (field, value) in members {
+            let value = serde_json::to_vec(value).chain_err(|| "Could not serialize JSON")?;
+            self.set(client, field, value)?;
+        }
+        Ok(())
+    }
+
+    /// Get the document as a JSON object, with each field's value parsed as JSON
+    pub fn get_json(&self, client: &mut KVSClient) -> Result<serde_json::Value> {
+        let mut object = serde_json::Map::new();
+        for (field, value) in self.get_all(client)? {
+            let value = serde_json::from_slice(&value)
+                .chain_err(|| format!("Field '{}' of document '{}' is not JSON", field, self.key))?;
+            object.insert(field, value);
+        }
+        Ok(serde_json::Value::Object(object))
+    }
+}
+
+impl KVSClient {
+    /*
+        HSET <key> <field> <value>
+     */
+    pub fn hset(&mut self, tokens: &[&str]) -> Result<String> {
+        debug!("HSET: {:?}", tokens);
+        if tokens.len() < 3 {
+            bail!("Usage: HSET <key> <field> <value>");
+        }
+        let document = Document::new(tokens[0]);
+        let value = self.renderer_for(&document.field_key(tokens[1]))?
+            .parse(&tokens[2..].join(" "))?;
+        document.set(self, tokens[1], value)?;
+        Ok("Success!".into())
+    }
+
+    /*
+        HGET <key> <field>
+     */
+    pub fn hget(&mut self, tokens: &[&str]) -> Result<String> {
+        debug!("HGET: {:?}", tokens);
+        if tokens.len() < 2 {
+            bail!("Usage: HGET <key> <field>");
+        }
+        let document = Document::new(tokens[0]);
+        let value = document.get(self, tokens[1])?;
+        self.renderer_for(&document.field_key(tokens[1]))?.render(&value)
+    }
+
+    /*
+        HGETALL <key>
+
+        Each field is displayed on its own line, in order
+     */
+    pub fn hgetall(&mut self, tokens: &[&str]) -> Result<String> {
+        debug!("HGETALL: {:?}", tokens);
+        let key = tokens.first().ok_or("Usage: HGETALL <key>")?;
+        let document = Document::new(key);
+        let mut lines = Vec::new();
+        for (field, value) in document.get_all(self)? {
+            let value = self.renderer_for(&document.field_key(&field))?.render(&value)?;
+            lines.push(format!("{}: {}", field, value));
+        }
+        Ok(lines.join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Document;
+
+    #[test]
+    fn fields_are_stored_under_the_document() {
+        assert_eq!(Document::new("users/42").field_key("email"), "users/42/email");
+    }
+}
@@ -0,0 +1,291 @@
+//! Encryption module encrypts the values written to `anna` before they leave the client, so
+//! that neither the KVS nodes nor the files of the `ebs` tier hold them in plaintext.
+//!
+//! Values are encrypted with XChaCha20-Poly1305, using keys loaded from a local keyring file.
+//! Encrypted values start with a header that records the id of the key used, so values written
+//! before a key was rotated are still decrypted with the key they were written with, and values
+//! written without encryption are read as they are.
+//!
+//! LWW and PRIORITY values are encrypted with a random nonce. The values of SET, ORDERED_SET
+//! and MULTI_CAUSAL lattices are encrypted deterministically, with a nonce that is an HMAC of
+//! the value, so equal values encrypt to equal bytes and the lattices still merge them. The HMAC
+//! key is derived from the encryption key rather than being the encryption key itself. Values
+//! written with a key that has since been rotated are not equal to those written with the new
+//! key, so duplicates are dropped as they are read. ORDERED_SET values are sorted once they have
+//! been decrypted, as their ciphertexts do not sort like their plaintexts.
+//!
+//! The priorities of PRIORITY lattices stay in plaintext, as the KVS nodes compare them to merge
+//! values, as do the vector clocks and dependencies of causal lattices.
+//!
+//! `Key`s stay in plaintext so that they can still be routed. This includes the keys of the
+//! secondary indexes, which contain the values of the fields they index.
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hmac::{Hmac, Mac};
+use serde_derive::Deserialize;
+use sha2::Sha256;
+
+use crate::errors::*;
+use crate::kvs_client::KVSClient;
+
+// Marks the start of an encrypted value, followed by one byte with the length of the key id,
+// the key id, the nonce and the ciphertext
+const HEADER_MAGIC: &[u8] = b"\xA7E";
+const NONCE_LENGTH: usize = 24;
+const KEY_LENGTH: usize = 32;
+
+// The message the key that derives nonces for deterministic encryption is derived with, as an
+// HMAC keyed by the encryption key, so that the encryption key is not itself used with HMAC
+const NONCE_KEY_CONTEXT: &[u8] = b"anna deterministic nonce key\0";
+
+/*
+    The keyring file, a yaml file of keys in hex, by key id
+ */
+#[derive(Deserialize)]
+struct KeyringFile {
+    // the id of the key new values are encrypted with
+    active: String,
+    keys: HashMap<String, String>,
+}
+
+/// `Keyring` holds the keys values are encrypted with, by key id. New values are encrypted with
+/// the active key, and the other keys are kept to decrypt values written before it was rotated.
+pub struct Keyring {
+    active: String,
+    keys: HashMap<String, [u8; KEY_LENGTH]>,
+}
+
+impl Keyring {
+    /// Create a keyring of `keys` that encrypts new values with the key with id `active`
+    pub fn new(active: &str, keys: HashMap<String, [u8; KEY_LENGTH]>) -> Result<Keyring> {
+        if !keys.contains_key(active) {
+            bail!("The active key '{}' is not in the keyring", active);
+        }
+        if let Some(id) = keys.keys().find(|id| id.len() > u8::MAX as usize) {
+            bail!("Key id '{}' is longer than {} bytes", id, u8::MAX);
+        }
+        Ok(Keyring { active: active.into(), keys })
+    }
+
+    /// Read the `Keyring` from a yaml keyring file, with the id of the active key under `active`
+    /// and 256-bit keys in hex under `keys`
+    pub fn read(filename: &str) -> Result<Keyring> {
+        let mut file = File::open(filename)
+            .chain_err(|| format!("Could not open keyring file '{}'", filename))?;
+        let mut content = String::new();
+        file.read_to_string(&mut content)
+            .chain_err(|| format!("Could not read keyring file '{}'", filename))?;
+        let keyring: KeyringFile = serde_yaml::from_str(&content)
+            .chain_err(|| format!("Error deserializing keyring file '{}'", filename))?;
+
+        let mut keys = HashMap::new();
+        for (id, hex_key) in keyring.keys {
+            let mut key = [0; KEY_LENGTH];
+            hex::decode_to_slice(hex_key.trim(), &mut key)
+                .chain_err(|| format!("Key '{}' is not {} bytes of hex", id, KEY_LENGTH))?;
+            keys.insert(id, key);
+        }
+        Keyring::new(&keyring.active, keys)
+    }
+
+    /// Return the id of the key new values are encrypted with
+    pub fn active_key_id(&self) -> &str {
+        &self.active
+    }
+
+    /// Encrypt `plaintext` with the active key and a random nonce
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        self.seal(plaintext, &rand::random::<[u8; NONCE_LENGTH]>())
+    }
+
+    /// Encrypt `plaintext` with the active key and a nonce derived from it, so that equal
+    /// plaintexts encrypt to equal bytes
+    pub fn encrypt_deterministic(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let nonce_key = hmac(&self.keys[&self.active], NONCE_KEY_CONTEXT)?;
+        let mut nonce = [0; NONCE_LENGTH];
+        nonce.copy_from_slice(&hmac(&nonce_key, plaintext)?[..NONCE_LENGTH]);
+        self.seal(plaintext, &nonce)
+    }
+
+    /// Decrypt the stored `bytes` of a value with the key recorded in their header, or return
+    /// them as they are if they were not encrypted
+    pub fn decrypt(&self, bytes: Vec<u8>) -> Result<Vec<u8>> {
+        let sealed = match split_header(&bytes)? {
+            Some(sealed) => sealed,
+            None => return Ok(bytes),
+        };
+        let key = self.keys.get(sealed.key_id).ok_or_else(||
+            format!("Value is encrypted with key '{}', which is not in the keyring", sealed.key_id))?;
+        XChaCha20Poly1305::new(key.into())
+            .decrypt(XNonce::from_slice(sealed.nonce), sealed.ciphertext)
+            .map_err(|_| format!("Could not decrypt value with key '{}'", sealed.key_id).into())
+    }
+
+    /*
+        Encrypt `plaintext` with the active key and `nonce`, prefixed with the header
+     */
+    fn seal(&self, plaintext: &[u8], nonce: &[u8; NONCE_LENGTH]) -> Result<Vec<u8>> {
+        let key = &self.keys[&self.active];
+        let ciphertext = XChaCha20Poly1305::new(key.into())
+            .encrypt(XNonce::from_slice(nonce), plaintext)
+            .map_err(|_| Error::from(format!("Could not encrypt value with key '{}'", self.active)))?;
+
+        let mut bytes = HEADER_MAGIC.to_vec();
+        bytes.push(self.active.len() as u8);
+        bytes.extend_from_slice(self.active.as_bytes());
+        bytes.extend_from_slice(nonce);
+        bytes.extend(ciphertext);
+        Ok(bytes)
+    }
+}
+
+/*
+    Return the HMAC-SHA256 of `message` with `key`
+ */
+fn hmac(key: &[u8], message: &[u8]) -> Result<[u8; KEY_LENGTH]> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).chain_err(|| "Could not create HMAC")?;
+    mac.update(message);
+    Ok(mac.finalize().into_bytes().into())
+}
+
+/// Return the id of the key the stored `bytes` of a value were encrypted with, or None if they
+/// are not encrypted
+pub fn key_id_of(bytes: &[u8]) -> Option<&str> {
+    split_header(bytes).ok().flatten().map(|sealed| sealed.key_id)
+}
+
+/*
+    The parts of the stored bytes of an encrypted value
+ */
+struct Sealed<'a> {
+    key_id: &'a str,
+    nonce: &'a [u8],
+    ciphertext: &'a [u8],
+}
+
+/*
+    Split the stored `bytes` of an encrypted value into the key id, nonce and ciphertext
+ */
+fn split_header(bytes: &[u8]) -> Result<Option<Sealed<'_>>> {
+    if !bytes.starts_with(HEADER_MAGIC) || bytes.len() == HEADER_MAGIC.len() {
+        return Ok(None);
+    }
+    let id_start = HEADER_MAGIC.len() + 1;
+    let nonce_start = id_start + bytes[HEADER_MAGIC.len()] as usize;
+    let ciphertext_start = nonce_start + NONCE_LENGTH;
+    if bytes.len() < ciphertext_start {
+        bail!("Encrypted value of {} bytes is too short", bytes.len());
+    }
+    let key_id = std::str::from_utf8(&bytes[id_start..nonce_start])
+        .chain_err(|| "Key id of encrypted value is not UTF-8")?;
+    Ok(Some(Sealed {
+        key_id,
+        nonce: &bytes[nonce_start..ciphertext_start],
+        ciphertext: &bytes[ciphertext_start..],
+    }))
+}
+
+impl KVSClient {
+    /*
+        Encrypt an LWW or PRIORITY `value` if a keyring is configured
+     */
+    pub(crate) fn encrypt_if_enabled(&self, value: Vec<u8>) -> Result<Vec<u8>> {
+        match self.get_keyring() {
+            Some(keyring) => keyring.encrypt(&value),
+            None => Ok(value),
+        }
+    }
+
+    /*
+        Encrypt the `values` of a SET, ORDERED_SET or MULTI_CAUSAL lattice deterministically if
+        a keyring is configured
+     */
+    pub(crate) fn encrypt_set_values(&self, values: Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>> {
+        match self.get_keyring() {
+            Some(keyring) => values.iter().map(|value| keyring.encrypt_deterministic(value)).collect(),
+            None => Ok(values),
+        }
+    }
+
+    /*
+        Decrypt the stored `bytes` of a value, if they were encrypted
+     */
+    pub(crate) fn decrypt_if_encrypted(&self, bytes: Vec<u8>) -> Result<Vec<u8>> {
+        match (self.get_keyring(), key_id_of(&bytes)) {
+            (Some(keyring), _) => keyring.decrypt(bytes),
+            (None, Some(id)) => bail!("Value is encrypted with key '{}', but no keyring is configured", id),
+            (None, None) => Ok(bytes),
+        }
+    }
+
+    /*
+        Decrypt the stored `values` of a SET, ORDERED_SET or MULTI_CAUSAL lattice, dropping the
+        duplicates of values encrypted with different keys
+     */
+    pub(crate) fn decrypt_set_values(&self, values: Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>> {
+        let mut seen = HashSet::new();
+        let mut decrypted = Vec::with_capacity(values.len());
+        for value in values {
+            let value = self.decrypt_if_encrypted(value)?;
+            if seen.insert(value.clone()) {
+                decrypted.push(value);
+            }
+        }
+        Ok(decrypted)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::{key_id_of, Keyring};
+
+    fn keyring(active: &str) -> Keyring {
+        let mut keys = HashMap::new();
+        keys.insert("2020-01".to_string(), [1; 32]);
+        keys.insert("2020-02".to_string(), [2; 32]);
+        Keyring::new(active, keys).expect("Could not create keyring")
+    }
+
+    #[test]
+    fn round_trip() {
+        let keyring = keyring("2020-01");
+        let bytes = keyring.encrypt(b"secret").expect("Could not encrypt");
+        assert_eq!(key_id_of(&bytes), Some("2020-01"));
+        assert_ne!(keyring.encrypt(b"secret").expect("Could not encrypt"), bytes);
+        assert_eq!(keyring.decrypt(bytes).expect("Could not decrypt"), b"secret");
+        assert_eq!(keyring.decrypt(b"plain".to_vec()).expect("Could not decrypt"), b"plain");
+    }
+
+    #[test]
+    fn deterministic_encryption() {
+        let keyring = keyring("2020-01");
+        let bytes = keyring.encrypt_deterministic(b"member").expect("Could not encrypt");
+        assert_eq!(keyring.encrypt_deterministic(b"member").expect("Could not encrypt"), bytes);
+        assert_ne!(keyring.encrypt_deterministic(b"other").expect("Could not encrypt"), bytes);
+        assert_eq!(keyring.decrypt(bytes).expect("Could not decrypt"), b"member");
+    }
+
+    #[test]
+    fn old_keys_decrypt_after_rotation() {
+        let bytes = keyring("2020-01").encrypt(b"secret").expect("Could not encrypt");
+        let rotated = keyring("2020-02");
+        assert_eq!(key_id_of(&rotated.encrypt(b"secret").expect("Could not encrypt")), Some("2020-02"));
+        assert_eq!(rotated.decrypt(bytes).expect("Could not decrypt"), b"secret");
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let keyring = keyring("2020-01");
+        let mut bytes = keyring.encrypt(b"secret").expect("Could not encrypt");
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert!(keyring.decrypt(bytes).is_err());
+        assert!(Keyring::new("missing", HashMap::new()).is_err());
+    }
+}
@@ -0,0 +1,57 @@
+//! Envelope module tags the values users write to LWW lattices, so that they cannot be confused
+//! with the headers the client adds to the values it stores.
+//!
+//! The client marks stored values with headers made of the byte 0xA7 and a letter: tombstones,
+//! expiry times, transaction records, chunk manifests, and compressed and encrypted values. Each
+//! value a user writes is stored after the plain value header, so a value that happens to start
+//! with the bytes of another header is not read as deleted, expired or encoded. The header is
+//! removed as the value is read. Values stored before the header was introduced have none, and
+//! are read as they are.
+
+// Marks the start of a value written by a user, which follows it as it was written
+const PLAIN_MAGIC: &[u8] = b"\xA7V";
+
+/// Return `value`, written by a user, tagged with the plain value header
+pub fn tag_plain(value: &[u8]) -> Vec<u8> {
+    let mut bytes = PLAIN_MAGIC.to_vec();
+    bytes.extend_from_slice(value);
+    bytes
+}
+
+/// Return the value a user wrote from the stored `bytes` of a value, once any other headers have
+/// been removed from them
+pub fn strip_plain(bytes: &[u8]) -> &[u8] {
+    bytes.strip_prefix(PLAIN_MAGIC).unwrap_or(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{strip_plain, tag_plain};
+    use crate::chunk::is_chunked;
+    use crate::compression::decompress;
+    use crate::encryption::key_id_of;
+    use crate::expiry::split_expiry;
+    use crate::ramp::decode_record;
+    use crate::tombstone::is_tombstone;
+
+    #[test]
+    fn values_with_headers_are_plain() {
+        for magic in &[b"\xA7D", b"\xA7X", b"\xA7C", b"\xA7Z", b"\xA7E", b"\xA7R", b"\xA7V"] {
+            let value = [&magic[..], b"\x01\x00\x00\x00\x00\x00\x00\x00\x07payload"].concat();
+            let stored = tag_plain(&value);
+            assert!(!is_tombstone(&stored));
+            assert_eq!(split_expiry(&stored).0, None);
+            assert!(!is_chunked(&stored));
+            assert_eq!(decompress(stored.clone()).expect("decompress"), stored);
+            assert_eq!(key_id_of(&stored), None);
+            assert_eq!(decode_record(&stored).expect("record"), None);
+            assert_eq!(strip_plain(&stored), &value[..]);
+        }
+        assert!(!is_tombstone(&tag_plain(b"\xA7D")));
+    }
+
+    #[test]
+    fn untagged_values_are_read_as_they_are() {
+        assert_eq!(strip_plain(b"written before"), b"written before");
+    }
+}
@@ -0,0 +1,357 @@
+//! Event log module provides append-only logs, for audit trails and event streams.
+//!
+//! Entries are stored in ORDERED_SET lattices, with each key holding the entries appended in one
+//! time bucket. Entries are encoded so that they sort by the time they were appended, and the
+//! buckets that have entries are recorded in another ORDERED_SET lattice key.
+//!
+//! Compaction copies the entries of old buckets into a single archive key, recorded in an LWW
+//! manifest, so that reads of old entries fetch one key rather than one key per bucket. Reads of
+//! compacted buckets only read the archive, so a bucket is only compacted once it is sealed, a
+//! while after its period has ended. Entries appended to a bucket after it has been compacted,
+//! by writers whose clocks are behind by more than that, are not read. Buckets are left in
+//! place, so if concurrent compactions lose an archive from the manifest, its buckets are read
+//! instead.
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+use log::debug;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::codec::Codec;
+use crate::errors::*;
+use crate::expiry::now_ms;
+use crate::kvs_client::{default_if_missing, KVSClient, Key};
+
+// The number of entries shown by TAIL if no count is given
+const DEFAULT_TAIL: usize = 10;
+
+// How long after its period ends a bucket is sealed, and can be compacted. This bounds the
+// clock skew between the writers of a log.
+const SEAL_AFTER: Duration = Duration::from_secs(5 * 60);
+
+/// `LogEntry` is one entry appended to an `EventLog`
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogEntry {
+    /// When the entry was appended, in ms since the UNIX epoch
+    pub timestamp: u64,
+    /// A unique id for the entry, starting with the id of the client that appended it
+    pub id: String,
+    /// The payload of the entry
+    pub payload: Vec<u8>,
+}
+
+impl LogEntry {
+    /*
+        Encode the entry so that entries sort by timestamp, then id
+     */
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = self.timestamp.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&(self.id.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(self.id.as_bytes());
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Result<LogEntry> {
+        if bytes.len() < 10 {
+            bail!("Log entry of {} bytes is too short", bytes.len());
+        }
+        let mut timestamp = [0; 8];
+        timestamp.copy_from_slice(&bytes[..8]);
+        let id_length = u16::from_be_bytes([bytes[8], bytes[9]]) as usize;
+        let id = bytes.get(10..10 + id_length).ok_or("Log entry id is truncated")?;
+        Ok(LogEntry {
+            timestamp: u64::from_be_bytes(timestamp),
+            id: String::from_utf8_lossy(id).into(),
+            payload: bytes[10 + id_length..].to_vec(),
+        })
+    }
+}
+
+/*
+    The buckets whose entries have been copied into an archive key
+ */
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Archive {
+    first_bucket: u64,
+    last_bucket: u64,
+}
+
+/*
+    The archives of a compacted log, stored in its manifest key
+ */
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+struct Manifest {
+    archives: Vec<Archive>,
+}
+
+impl Manifest {
+    fn archive_of(&self, bucket: u64) -> Option<&Archive> {
+        self.archives.iter().find(|archive| archive.first_bucket <= bucket && bucket <= archive.last_bucket)
+    }
+}
+
+/// `EventLog` is an append-only log stored in `anna` under `key`
+pub struct EventLog {
+    key: Key,
+    bucket_ms: u64,
+}
+
+impl EventLog {
+    /// Create a log stored under `key`, with one key per hour of entries
+    pub fn new(key: &str) -> Self {
+        Self::with_bucket(key, Duration::from_secs(60 * 60))
+    }
+
+    /// Create a log stored under `key`, with one key for the entries appended in each period of
+    /// `bucket`. All the writers and readers of a log must use the same bucket.
+    pub fn with_bucket(key: &str, bucket: Duration) -> Self {
+        EventLog { key: key.into(), bucket_ms: (bucket.as_millis() as u64).max(1) }
+    }
+
+    /// Append an entry with `payload` to the log, returning it
+    pub fn append(&self, client: &mut KVSClient, payload: &[u8]) -> Result<LogEntry> {
+        let entry = LogEntry { timestamp: now_ms(), id: client.generate_unique_id(), payload: payload.to_vec() };
+        let bucket = entry.timestamp / self.bucket_ms;
+        client.add_to_ordered_set(&self.bucket_key(bucket), vec!(entry.encode()))?;
+        client.add_to_ordered_set(&self.buckets_key(), vec!(bucket.to_be_bytes().to_vec()))?;
+        client.register_key(&self.key)?;
+        Ok(entry)
+    }
+
+    /// Read the entries appended from time `from` up to, but not including, time `to` (in ms since
+    /// the UNIX epoch), in order
+    pub fn range(&self, client: &mut KVSClient, from: u64, to: u64) -> Result<Vec<LogEntry>> {
+        let manifest = self.manifest(client)?;
+        let buckets = self.buckets(client)?.into_iter()
+            .filter(|bucket| from / self.bucket_ms <= *bucket && *bucket <= to / self.bucket_ms)
+            .collect::<Vec<u64>>();
+        Ok(self.read(client, &manifest, &buckets)?.into_iter()
+            .filter(|entry| from <= entry.timestamp && entry.timestamp < to)
+            .collect())
+    }
+
+    /// Read the last `count` entries appended, in order
+    pub fn tail(&self, client: &mut KVSClient, count: usize) -> Result<Vec<LogEntry>> {
+        let manifest = self.manifest(client)?;
+        let buckets = self.buckets(client)?;
+        let mut remaining = &buckets[..];
+        let mut entries = Vec::new();
+        while let Some(last) = remaining.last() {
+            // the buckets of an archive are read together
+            let first = manifest.archive_of(*last).map_or(*last, |archive| archive.first_bucket);
+            let split = remaining.partition_point(|bucket| *bucket < first);
+            let mut older = self.read(client, &manifest, &remaining[split..])?;
+            older.append(&mut entries);
+            entries = older;
+            remaining = &remaining[..split];
+            if entries.len() >= count {
+                break;
+            }
+        }
+        let skip = entries.len().saturating_sub(count);
+        Ok(entries.split_off(skip))
+    }
+
+    /// Copy the entries of the sealed buckets that have not already been compacted into an
+    /// archive key. Returns the number of buckets compacted.
+    pub fn compact(&self, client: &mut KVSClient) -> Result<usize> {
+        let mut manifest = self.manifest(client)?;
+        let sealed = sealed_before(now_ms(), self.bucket_ms);
+        let buckets = self.buckets(client)?.into_iter()
+            .filter(|bucket| *bucket < sealed && manifest.archive_of(*bucket).is_none())
+            .collect::<Vec<u64>>();
+        let (first_bucket, last_bucket) = match (buckets.first(), buckets.last()) {
+            (Some(first), Some(last)) => (*first, *last),
+            _ => return Ok(0),
+        };
+
+        let mut entries = Vec::new();
+        for bucket in &buckets {
+            entries.extend(default_if_missing(client.get_ordered_set_values(&self.bucket_key(*bucket)))?);
+        }
+        debug!("Compacting {} entries of log '{}' into an archive", entries.len(), self.key);
+        client.add_to_ordered_set(&self.archive_key(first_bucket, last_bucket), entries)?;
+
+        manifest.archives.push(Archive { first_bucket, last_bucket });
+        client.put_typed(&self.manifest_key(), &manifest, Codec::Json)?;
+        Ok(buckets.len())
+    }
+
+    /*
+        Return the buckets that have entries, in order
+     */
+    fn buckets(&self, client: &mut KVSClient) -> Result<Vec<u64>> {
+        default_if_missing(client.get_ordered_set_values(&self.buckets_key()))?.iter()
+            .map(|bucket| {
+                let mut bytes = [0; 8];
+                if bucket.len() != bytes.len() {
+                    bail!("Log '{}' has an invalid bucket", self.key);
+                }
+                bytes.copy_from_slice(bucket);
+                Ok(u64::from_be_bytes(bytes))
+            })
+            .collect()
+    }
+
+    fn manifest(&self, client: &mut KVSClient) -> Result<Manifest> {
+        default_if_missing(client.get_typed(&self.manifest_key()))
+    }
+
+    /*
+        Read the entries of `buckets`, from the archives of those that have been compacted in
+        `manifest`, in order
+     */
+    fn read(&self, client: &mut KVSClient, manifest: &Manifest, buckets: &[u64]) -> Result<Vec<LogEntry>> {
+        let keys = buckets.iter()
+            .map(|bucket| match manifest.archive_of(*bucket) {
+                Some(archive) => self.archive_key(archive.first_bucket, archive.last_bucket),
+                None => self.bucket_key(*bucket),
+            })
+            .collect::<BTreeSet<Key>>();
+
+        // the keys of buckets and archives do not sort by time, so their entries are sorted
+        let mut entries = BTreeMap::new();
+        for key in keys {
+            for bytes in default_if_missing(client.get_ordered_set_values(&key))? {
+                let entry = LogEntry::decode(&bytes)?;
+                if buckets.contains(&(entry.timestamp / self.bucket_ms)) {
+                    entries.insert((entry.timestamp, entry.id.clone()), entry);
+                }
+            }
+        }
+        Ok(entries.into_values().collect())
+    }
+
+    fn bucket_key(&self, bucket: u64) -> Key {
+        format!("{}/{}", self.key, bucket)
+    }
+
+    fn buckets_key(&self) -> Key {
+        format!("{}/buckets", self.key)
+    }
+
+    fn archive_key(&self, first_bucket: u64, last_bucket: u64) -> Key {
+        format!("{}/archive/{}-{}", self.key, first_bucket, last_bucket)
+    }
+
+    fn manifest_key(&self) -> Key {
+        format!("{}/manifest", self.key)
+    }
+}
+
+/*
+    Return the first bucket of `bucket_ms` that is not yet sealed at time `now`
+ */
+fn sealed_before(now: u64, bucket_ms: u64) -> u64 {
+    now.saturating_sub(SEAL_AFTER.as_millis() as u64) / bucket_ms
+}
+
+impl KVSClient {
+    /*
+        Format `entries` one per line, rendering payloads with the renderer for `key`
+     */
+    fn format_entries(&mut self, key: &str, entries: &[LogEntry]) -> Result<String> {
+        let renderer = self.renderer_for(key)?;
+        entries.iter()
+            .map(|entry| Ok(format!("{} {} {}", entry.timestamp, entry.id, renderer.render(&entry.payload)?)))
+            .collect::<Result<Vec<String>>>()
+            .map(|lines| lines.join("\n"))
+    }
+
+    /*
+        APPEND <log> <payload>
+     */
+    pub fn append(&mut self, tokens: &[&str]) -> Result<String> {
+        debug!("APPEND: {:?}", tokens);
+        if tokens.len() < 2 {
+            bail!("Usage: APPEND <log> <payload>");
+        }
+        let payload = self.renderer_for(tokens[0])?.parse(&tokens[1..].join(" "))?;
+        let entry = EventLog::new(tokens[0]).append(self, &payload)?;
+        Ok(format!("Appended at {}", entry.timestamp))
+    }
+
+    /*
+        RANGE <log> <from> <to>
+
+        Times are in ms since the UNIX epoch
+     */
+    pub fn range(&mut self, tokens: &[&str]) -> Result<String> {
+        debug!("RANGE: {:?}", tokens);
+        if tokens.len() < 3 {
+            bail!("Usage: RANGE <log> <from> <to>");
+        }
+        let from = tokens[1].parse().chain_err(|| format!("'{}' is not a valid time", tokens[1]))?;
+        let to = tokens[2].parse().chain_err(|| format!("'{}' is not a valid time", tokens[2]))?;
+        let entries = EventLog::new(tokens[0]).range(self, from, to)?;
+        self.format_entries(tokens[0], &entries)
+    }
+
+    /*
+        TAIL <log> [<count>]
+     */
+    pub fn tail(&mut self, tokens: &[&str]) -> Result<String> {
+        debug!("TAIL: {:?}", tokens);
+        let key = tokens.first().ok_or("Usage: TAIL <log> [<count>]")?;
+        let count = match tokens.get(1) {
+            Some(count) => count.parse().chain_err(|| format!("'{}' is not a valid count", count))?,
+            None => DEFAULT_TAIL,
+        };
+        let entries = EventLog::new(key).tail(self, count)?;
+        self.format_entries(key, &entries)
+    }
+
+    /*
+        LOG_COMPACT <log>
+     */
+    pub fn log_compact(&mut self, tokens: &[&str]) -> Result<String> {
+        debug!("LOG_COMPACT: {:?}", tokens);
+        let key = tokens.first().ok_or("Usage: LOG_COMPACT <log>")?;
+        let compacted = EventLog::new(key).compact(self)?;
+        Ok(format!("Compacted {} buckets", compacted))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{sealed_before, Archive, LogEntry, Manifest};
+
+    fn entry(timestamp: u64, id: &str, payload: &[u8]) -> LogEntry {
+        LogEntry { timestamp, id: id.into(), payload: payload.to_vec() }
+    }
+
+    #[test]
+    fn entry_round_trip() {
+        let original = entry(1_600_000_000_000, "client:1", b"order placed");
+        assert_eq!(LogEntry::decode(&original.encode()).expect("Could not decode"), original);
+        assert!(LogEntry::decode(b"short").is_err());
+    }
+
+    #[test]
+    fn entries_sort_by_time() {
+        let mut encoded = [entry(300, "a", b"third").encode(), entry(2, "z", b"first").encode(),
+                           entry(2, "zz", b"second").encode()];
+        encoded.sort();
+        let payloads = encoded.iter()
+            .map(|bytes| LogEntry::decode(bytes).expect("Could not decode").payload)
+            .collect::<Vec<Vec<u8>>>();
+        assert_eq!(payloads, vec!(b"first".to_vec(), b"second".to_vec(), b"third".to_vec()));
+    }
+
+    #[test]
+    fn buckets_are_found_in_archives() {
+        let manifest = Manifest { archives: vec!(Archive { first_bucket: 3, last_bucket: 7 }) };
+        assert!(manifest.archive_of(5).is_some());
+        assert!(manifest.archive_of(8).is_none());
+    }
+
+    #[test]
+    fn buckets_are_sealed_after_their_period() {
+        let minute = 60_000;
+        // the bucket ending at 10 minutes is sealed 5 minutes later
+        assert_eq!(sealed_before(14 * minute, minute), 9);
+        assert_eq!(sealed_before(15 * minute, minute), 10);
+        assert_eq!(sealed_before(minute, minute), 0);
+    }
+}
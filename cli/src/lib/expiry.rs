@@ -0,0 +1,190 @@
+//! Expiry module emulates keys with a time to live. The time an LWW value expires is stored in
+//! a header in front of the value, and reads treat a key whose value has expired as one that
+//! does not exist.
+//!
+//! Expired keys still hold their value in `anna` until they are swept. Each key written with a
+//! time to live is added to an expiry index, a SET lattice key per bucket of expiry times, and
+//! the sweeper tombstones the expired keys in the buckets that have passed since the last sweep.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::debug;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::codec::Codec;
+use crate::envelope::tag_plain;
+use crate::errors::*;
+use crate::kvs_client::{decode_tuple, default_if_missing, KVSClient, CLIENT_METADATA_PREFIX};
+use crate::proto::anna::{KeyTuple, LatticeType, LwwValue};
+
+// Marks the start of a value with an expiry time, followed by the time it expires as
+// milliseconds since the UNIX epoch (big-endian)
+const EXPIRY_MAGIC: &[u8] = b"\xA7X";
+const HEADER_LENGTH: usize = 10;
+
+// The range of expiry times, in ms, covered by each key of the expiry index
+const BUCKET_MS: u64 = 5 * 60 * 1000;
+
+/*
+    The progress of the sweeper, stored in a reserved key
+ */
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SweepProgress {
+    // the last bucket all of whose keys have been swept
+    swept: Option<u64>,
+}
+
+/// Return the current time in milliseconds since the UNIX epoch
+pub fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::from_millis(0)).as_millis() as u64
+}
+
+/// Prefix `value` with a header recording that it expires at `expires_at` (in ms since the
+/// UNIX epoch)
+pub fn with_expiry(value: &[u8], expires_at: u64) -> Vec<u8> {
+    let mut bytes = EXPIRY_MAGIC.to_vec();
+    bytes.extend_from_slice(&expires_at.to_be_bytes());
+    bytes.extend_from_slice(value);
+    bytes
+}
+
+/// Split the stored `bytes` of a value into the time it expires, if it was written with a time
+/// to live, and the value itself
+pub fn split_expiry(bytes: &[u8]) -> (Option<u64>, &[u8]) {
+    if bytes.len() < HEADER_LENGTH || !bytes.starts_with(EXPIRY_MAGIC) {
+        return (None, bytes);
+    }
+    let mut expires_at = [0; 8];
+    expires_at.copy_from_slice(&bytes[EXPIRY_MAGIC.len()..HEADER_LENGTH]);
+    (Some(u64::from_be_bytes(expires_at)), &bytes[HEADER_LENGTH..])
+}
+
+/// Return true if the stored `bytes` of a value have expired
+pub fn has_expired(bytes: &[u8]) -> bool {
+    matches!(split_expiry(bytes).0, Some(expires_at) if expires_at <= now_ms())
+}
+
+/// Return true if `tuple` holds an LWW lattice whose value has expired
+pub fn is_expired(tuple: &KeyTuple) -> bool {
+    tuple.lattice_type() == LatticeType::Lww &&
+        decode_tuple::<LwwValue>(tuple, LatticeType::Lww)
+            .map(|lww| has_expired(&lww.value))
+            .unwrap_or(false)
+}
+
+/*
+    The bucket of the expiry index that `expires_at` falls in
+ */
+fn bucket_of(expires_at: u64) -> u64 {
+    expires_at / BUCKET_MS
+}
+
+fn index_key(bucket: u64) -> String {
+    format!("{}expiry|{}", CLIENT_METADATA_PREFIX, bucket)
+}
+
+// A PRIORITY lattice key, which keeps the earliest bucket any key has been added to
+fn first_bucket_key() -> String {
+    format!("{}expiry|first", CLIENT_METADATA_PREFIX)
+}
+
+fn progress_key() -> String {
+    format!("{}expiry|progress", CLIENT_METADATA_PREFIX)
+}
+
+impl KVSClient {
+    /// Write `value` to the LWW lattice of `key`, to expire after `ttl`. Returns the timestamp
+    /// it was written with.
+    pub fn put_with_ttl(&mut self, key: &str, value: &[u8], ttl: Duration) -> Result<u64> {
+        let expires_at = now_ms() + ttl.as_millis() as u64;
+        let timestamp = self.next_timestamp();
+        self.put_lww_reporting_at(key, with_expiry(&tag_plain(value), expires_at), timestamp)?;
+
+        let bucket = bucket_of(expires_at);
+        self.add_to_set(&index_key(bucket), vec!(key.as_bytes().to_vec()))
+            .chain_err(|| format!("Could not add key '{}' to the expiry index", key))?;
+        self.put_priority_value(&first_bucket_key(), bucket as f64, vec!())?;
+        self.register_key(key)?;
+        Ok(timestamp)
+    }
+
+    /// Tombstone the keys in the expiry index whose values have expired, returning them. Keys
+    /// that have been written again since they were added to the index are left alone.
+    pub fn expire_sweep(&mut self) -> Result<Vec<String>> {
+        let progress: SweepProgress = default_if_missing(self.get_typed(&progress_key()))?;
+        let first = match progress.swept {
+            Some(swept) => swept + 1,
+            None => match self.get_priority_value(&first_bucket_key()) {
+                Ok(first) => first.priority as u64,
+                Err(Error(ErrorKind::KeyDoesNotExist(_), _)) => return Ok(Vec::new()),
+                Err(e) => return Err(e),
+            }
+        };
+        let now = now_ms();
+        let current = bucket_of(now);
+
+        let mut expired = Vec::new();
+        for bucket in first..=current {
+            for key in default_if_missing(self.get_set_values(&index_key(bucket)))? {
+                let key = String::from_utf8_lossy(&key).to_string();
+                let lww: LwwValue = match self.get_lattice(&key) {
+                    Ok(tuple) if tuple.lattice_type() == LatticeType::Lww =>
+                        decode_tuple(&tuple, LatticeType::Lww)?,
+                    Ok(_) | Err(Error(ErrorKind::KeyDoesNotExist(_), _)) => continue,
+                    Err(e) => return Err(e),
+                };
+
+                // the expiry header is inside the value if it was compressed or chunked
+                let value = self.decode_stored(lww.value)?;
+                if let (Some(expires_at), _) = split_expiry(&value) {
+                    if expires_at <= now {
+                        debug!("Key '{}' expired at {}", key, expires_at);
+                        // a write made after the expired one still wins over the tombstone
+                        self.delete_written_at(&key, lww.timestamp)?;
+                        expired.push(key);
+                    }
+                }
+            }
+        }
+
+        // keys may still be added to the current bucket, so it is swept again next time
+        if current > first {
+            self.put_typed(&progress_key(), &SweepProgress { swept: Some(current - 1) }, Codec::Json)?;
+        }
+        Ok(expired)
+    }
+
+    /*
+        PUT_TTL <key> <seconds> <value>
+     */
+    pub fn put_ttl(&mut self, tokens: &[&str]) -> Result<String> {
+        debug!("PUT_TTL: {:?}", tokens);
+        if tokens.len() < 3 {
+            bail!("Usage: PUT_TTL <key> <seconds> <value>");
+        }
+        let seconds: u64 = tokens[1].parse()
+            .chain_err(|| format!("'{}' is not a valid number of seconds", tokens[1]))?;
+        let value = self.renderer_for(tokens[0])?.parse(&tokens[2..].join(" "))?;
+        self.put_with_ttl(tokens[0], &value, Duration::from_secs(seconds))?;
+        Ok("Success!".into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{has_expired, now_ms, split_expiry, with_expiry};
+
+    #[test]
+    fn expiry_round_trip() {
+        let bytes = with_expiry(b"session", 1_600_000_000_000);
+        assert_eq!(split_expiry(&bytes), (Some(1_600_000_000_000), &b"session"[..]));
+        assert!(has_expired(&bytes));
+    }
+
+    #[test]
+    fn values_without_expiry_never_expire() {
+        assert_eq!(split_expiry(b"plain"), (None, &b"plain"[..]));
+        assert!(!has_expired(b"plain"));
+        assert!(!has_expired(&with_expiry(b"later", now_ms() + 60_000)));
+    }
+}
@@ -0,0 +1,199 @@
+//! Graph module stores directed graphs as adjacency lists, with the neighbors of each vertex
+//! held in a SET lattice key, so edges can be added concurrently without coordination.
+//!
+//! Breadth first searches read the neighbor lists of each level of the search with batched
+//! multi-key reads, so a search needs one round of requests per hop.
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use log::debug;
+
+use crate::display::format_set;
+use crate::errors::*;
+use crate::kvs_client::{decode_tuple, KVSClient, Key};
+use crate::proto::anna::{LatticeType, SetValue};
+
+/// `Graph` is a directed graph stored in `anna` under `key`
+pub struct Graph {
+    key: Key,
+}
+
+impl Graph {
+    /// Create a graph stored under `key`
+    pub fn new(key: &str) -> Self {
+        Graph { key: key.into() }
+    }
+
+    /// Add an edge from vertex `from` to vertex `to`
+    pub fn add_edge(&self, client: &mut KVSClient, from: &str, to: &str) -> Result<()> {
+        client.add_to_set(&self.vertex_key(from), vec!(to.as_bytes().to_vec()))?;
+        client.register_key(&self.key)
+    }
+
+    /// Return the vertices that `vertex` has an edge to
+    pub fn neighbors(&self, client: &mut KVSClient, vertex: &str) -> Result<BTreeSet<String>> {
+        Ok(self.read_neighbors(client, &[vertex.to_string()])?
+            .remove(vertex).unwrap_or_default().into_iter().collect())
+    }
+
+    /// Search breadth first from `start`, following at most `max_hops` edges and visiting at most
+    /// `max_vertices` vertices (not counting `start`). Returns the vertices found with the number
+    /// of hops to reach them, in the order they were found.
+    pub fn bfs(&self, client: &mut KVSClient, start: &str, max_hops: usize, max_vertices: usize)
+               -> Result<Vec<(String, usize)>> {
+        bfs(start, max_hops, max_vertices, |vertices| self.read_neighbors(client, vertices))
+    }
+
+    /// Return the vertices reachable from `start` by following between 1 and `k` edges
+    pub fn k_hop(&self, client: &mut KVSClient, start: &str, k: usize) -> Result<BTreeSet<String>> {
+        Ok(self.bfs(client, start, k, usize::MAX)?.into_iter().map(|(vertex, _)| vertex).collect())
+    }
+
+    /*
+        Read the neighbors of all of `vertices` in one batch
+     */
+    fn read_neighbors(&self, client: &mut KVSClient, vertices: &[String])
+                      -> Result<HashMap<String, Vec<String>>> {
+        let keys = vertices.iter().map(|vertex| self.vertex_key(vertex)).collect::<Vec<Key>>();
+        let tuples = client.get_lattices(&keys)?;
+
+        let mut neighbors = HashMap::new();
+        for (vertex, key) in vertices.iter().zip(keys) {
+            if let Some(tuple) = tuples.get(&key) {
+                let set: SetValue = decode_tuple(tuple, LatticeType::Set)?;
+                neighbors.insert(vertex.clone(), client.decrypt_set_values(set.values)?.iter()
+                    .map(|neighbor| String::from_utf8_lossy(neighbor).to_string())
+                    .collect());
+            }
+        }
+        Ok(neighbors)
+    }
+
+    fn vertex_key(&self, vertex: &str) -> Key {
+        format!("{}/{}", self.key, vertex)
+    }
+}
+
+/*
+    Search breadth first from `start`, using `read` to get the neighbors of each level
+ */
+fn bfs<F>(start: &str, max_hops: usize, max_vertices: usize, mut read: F) -> Result<Vec<(String, usize)>>
+    where F: FnMut(&[String]) -> Result<HashMap<String, Vec<String>>> {
+    let mut visited = HashSet::new();
+    visited.insert(start.to_string());
+    let mut found = Vec::new();
+    let mut frontier = vec!(start.to_string());
+
+    for hops in 1..=max_hops {
+        if frontier.is_empty() {
+            break;
+        }
+        debug!("Reading the neighbors of {} vertices", frontier.len());
+        let mut neighbors = read(&frontier)?;
+
+        let mut next = Vec::new();
+        for vertex in &frontier {
+            let mut vertex_neighbors = neighbors.remove(vertex).unwrap_or_default();
+            // sets are unordered, so sort to make the search deterministic
+            vertex_neighbors.sort();
+            for neighbor in vertex_neighbors {
+                if found.len() == max_vertices {
+                    return Ok(found);
+                }
+                if visited.insert(neighbor.clone()) {
+                    found.push((neighbor.clone(), hops));
+                    next.push(neighbor);
+                }
+            }
+        }
+        frontier = next;
+    }
+    Ok(found)
+}
+
+impl KVSClient {
+    /*
+        EDGE_ADD <graph> <from> <to>
+     */
+    pub fn edge_add(&mut self, tokens: &[&str]) -> Result<String> {
+        debug!("EDGE_ADD: {:?}", tokens);
+        if tokens.len() < 3 {
+            bail!("Usage: EDGE_ADD <graph> <from> <to>");
+        }
+        Graph::new(tokens[0]).add_edge(self, tokens[1], tokens[2])?;
+        Ok("Success!".into())
+    }
+
+    /*
+        NEIGHBORS <graph> <vertex>
+     */
+    pub fn neighbors(&mut self, tokens: &[&str]) -> Result<String> {
+        debug!("NEIGHBORS: {:?}", tokens);
+        if tokens.len() < 2 {
+            bail!("Usage: NEIGHBORS <graph> <vertex>");
+        }
+        let neighbors = Graph::new(tokens[0]).neighbors(self, tokens[1])?;
+        Ok(format_set(&neighbors.into_iter().collect::<Vec<String>>()))
+    }
+
+    /*
+        KHOP <graph> <vertex> <k>
+     */
+    pub fn khop(&mut self, tokens: &[&str]) -> Result<String> {
+        debug!("KHOP: {:?}", tokens);
+        if tokens.len() < 3 {
+            bail!("Usage: KHOP <graph> <vertex> <k>");
+        }
+        let k = tokens[2].parse().chain_err(|| format!("'{}' is not a valid number of hops", tokens[2]))?;
+        let vertices = Graph::new(tokens[0]).k_hop(self, tokens[1], k)?;
+        Ok(format_set(&vertices.into_iter().collect::<Vec<String>>()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::bfs;
+    use crate::errors::*;
+
+    fn graph(edges: &[(&str, &str)]) -> HashMap<String, Vec<String>> {
+        let mut graph: HashMap<String, Vec<String>> = HashMap::new();
+        for (from, to) in edges {
+            graph.entry(from.to_string()).or_default().push(to.to_string());
+        }
+        graph
+    }
+
+    fn search(edges: &[(&str, &str)], max_hops: usize, max_vertices: usize) -> (Vec<(String, usize)>, usize) {
+        let graph = graph(edges);
+        let mut reads = 0;
+        let found = bfs("a", max_hops, max_vertices, |vertices| -> Result<_> {
+            reads += 1;
+            Ok(vertices.iter()
+                .filter_map(|vertex| graph.get(vertex).map(|neighbors| (vertex.clone(), neighbors.clone())))
+                .collect())
+        }).expect("Search failed");
+        (found, reads)
+    }
+
+    #[test]
+    fn one_batch_per_hop() {
+        let edges = [("a", "b"), ("a", "c"), ("b", "d"), ("c", "d"), ("d", "a"), ("d", "e")];
+        let (found, reads) = search(&edges, 2, usize::MAX);
+        assert_eq!(found, vec!(("b".into(), 1), ("c".into(), 1), ("d".into(), 2)));
+        assert_eq!(reads, 2);
+    }
+
+    #[test]
+    fn bounded_by_vertices() {
+        let edges = [("a", "b"), ("a", "c"), ("b", "d")];
+        assert_eq!(search(&edges, 10, 2).0, vec!(("b".into(), 1), ("c".into(), 1)));
+    }
+
+    #[test]
+    fn stops_when_nothing_is_left() {
+        let (found, reads) = search(&[("a", "b")], 10, usize::MAX);
+        assert_eq!(found.len(), 1);
+        assert_eq!(reads, 2);
+    }
+}
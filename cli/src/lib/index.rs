@@ -0,0 +1,257 @@
+//! Index module maintains secondary indexes client-side, as `anna` only supports lookup by
+//! primary key.
+//!
+//! An index is defined by a key prefix and an extractor, which returns the terms to index a
+//! value under. Each time an LWW value is written to a key with the prefix, the key is added to
+//! the SET lattice index key of each term extracted from the value. As SET lattices only grow,
+//! a key is not removed from the terms its old values were indexed under, so queries re-read
+//! each key found and only return those whose current value still has the term.
+use std::collections::BTreeSet;
+
+use log::debug;
+use serde::de::DeserializeOwned;
+
+use crate::codec;
+use crate::config::Config;
+use crate::display::format_set;
+use crate::errors::*;
+use crate::envelope::strip_plain;
+use crate::expiry::split_expiry;
+use crate::kvs_client::{default_if_missing, KVSClient, Key, CLIENT_METADATA_PREFIX};
+use crate::tombstone::is_tombstone;
+
+/// Returns the terms to index a stored value under
+pub type Extractor = Box<dyn Fn(&[u8]) -> Result<Vec<String>>>;
+
+/// `IndexDefinition` defines a secondary index over the values of keys with a prefix
+pub struct IndexDefinition {
+    name: String,
+    prefix: String,
+    extractor: Extractor,
+}
+
+impl IndexDefinition {
+    /// Define the index `name` on keys with `prefix`, using `extractor` to find the terms of
+    /// their stored values
+    pub fn new(name: &str, prefix: &str, extractor: Extractor) -> Self {
+        IndexDefinition { name: name.into(), prefix: prefix.into(), extractor }
+    }
+
+    /// Define the index `name` on keys with `prefix` holding typed values (see the `codec`
+    /// module), using `extract` to find the terms of the decoded values
+    pub fn typed<T, F>(name: &str, prefix: &str, extract: F) -> Self
+        where T: DeserializeOwned, F: Fn(&T) -> Vec<String> + 'static {
+        Self::new(name, prefix, Box::new(move |bytes| Ok(extract(&codec::decode(bytes)?))))
+    }
+
+    /// Define the index `name` on keys with `prefix` holding JSON or typed values, indexing them
+    /// under the value found at the JSON `pointer` (such as "/address/city"). Strings, numbers
+    /// and booleans are indexed as text, and arrays are indexed under each of their members.
+    pub fn json_pointer(name: &str, prefix: &str, pointer: &str) -> Self {
+        let pointer = pointer.to_string();
+        Self::new(name, prefix, Box::new(move |bytes| {
+            let value: serde_json::Value = match codec::codec_of(bytes) {
+                Some(_) => codec::decode(bytes)?,
+                None => serde_json::from_slice(bytes).chain_err(|| "Value is not JSON")?,
+            };
+            Ok(value.pointer(&pointer).map(terms_of).unwrap_or_default())
+        }))
+    }
+
+    /// Return the name of the index
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Return true if the index covers `key`
+    pub fn covers(&self, key: &str) -> bool {
+        key.starts_with(&self.prefix) && !key.starts_with(CLIENT_METADATA_PREFIX)
+    }
+
+    /// Return the terms to index the stored `bytes` of a value under. Deleted keys have no
+    /// terms.
+    pub fn extract(&self, bytes: &[u8]) -> Result<Vec<String>> {
+        let (_, value) = split_expiry(bytes);
+        if is_tombstone(value) {
+            return Ok(Vec::new());
+        }
+        (self.extractor)(strip_plain(value))
+    }
+
+    fn term_key(&self, term: &str) -> Key {
+        format!("{}index|{}|terms|{}", CLIENT_METADATA_PREFIX, self.name, term)
+    }
+
+    // the SET lattice key of all the keys the index has seen
+    fn keys_key(&self) -> Key {
+        format!("{}index|{}|keys", CLIENT_METADATA_PREFIX, self.name)
+    }
+}
+
+/*
+    Return the terms to index a JSON value under
+ */
+fn terms_of(value: &serde_json::Value) -> Vec<String> {
+    match value {
+        serde_json::Value::String(text) => vec!(text.clone()),
+        serde_json::Value::Number(number) => vec!(number.to_string()),
+        serde_json::Value::Bool(boolean) => vec!(boolean.to_string()),
+        serde_json::Value::Array(members) => members.iter().flat_map(terms_of).collect(),
+        serde_json::Value::Null | serde_json::Value::Object(_) => Vec::new(),
+    }
+}
+
+/// `IndexRegistry` holds the secondary indexes a client maintains
+#[derive(Default)]
+pub struct IndexRegistry {
+    indexes: Vec<IndexDefinition>,
+}
+
+impl IndexRegistry {
+    /// Create a registry with the indexes in the `indexes` section of `config`
+    pub fn from_config(config: &Config) -> Self {
+        let mut registry = IndexRegistry::default();
+        for (name, prefix, field) in config.get_indexes() {
+            registry.register(IndexDefinition::json_pointer(name, prefix, field));
+        }
+        registry
+    }
+
+    /// Maintain the index `definition` on subsequent writes
+    pub fn register(&mut self, definition: IndexDefinition) {
+        self.indexes.push(definition);
+    }
+
+    /// Return the index called `name`
+    pub fn get(&self, name: &str) -> Result<&IndexDefinition> {
+        self.indexes.iter().find(|index| index.name == name)
+            .ok_or_else(|| format!("No index called '{}' is defined", name).into())
+    }
+
+    /// Return the index keys that `key` must be added to when `bytes` are written to it
+    pub fn updates(&self, key: &str, bytes: &[u8]) -> Vec<Key> {
+        let mut updates = Vec::new();
+        for index in self.indexes.iter().filter(|index| index.covers(key)) {
+            match index.extract(bytes) {
+                Ok(terms) => {
+                    updates.push(index.keys_key());
+                    updates.extend(terms.iter().map(|term| index.term_key(term)));
+                }
+                Err(e) => debug!("Value of key '{}' could not be indexed by '{}': {}", key, index.name, e),
+            }
+        }
+        updates
+    }
+}
+
+impl KVSClient {
+    /// Return the keys indexed by `index` under `term`, whose current values have the term
+    pub fn query_index(&mut self, index: &str, term: &str) -> Result<BTreeSet<Key>> {
+        let term_key = self.get_indexes().get(index)?.term_key(term);
+        let mut keys = BTreeSet::new();
+        for key in default_if_missing(self.get_set_values(&term_key))? {
+            let key = String::from_utf8_lossy(&key).to_string();
+            if self.index_terms(index, &key)?.iter().any(|current| current == term) {
+                keys.insert(key);
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Re-index every key `index` has seen, the keys in the catalog that it covers, and `keys`,
+    /// under the terms of their current values. This indexes keys written before the index was
+    /// defined.
+    pub fn rebuild_index(&mut self, index: &str, keys: &[&str]) -> Result<usize> {
+        let definition = self.get_indexes().get(index)?;
+        let (keys_key, prefix) = (definition.keys_key(), definition.prefix.clone());
+        let mut all_keys = default_if_missing(self.get_set_values(&keys_key))?.iter()
+            .map(|key| String::from_utf8_lossy(key).to_string())
+            .collect::<BTreeSet<Key>>();
+        all_keys.extend(self.list_keys(&prefix)?);
+        all_keys.extend(keys.iter().map(|key| key.to_string()));
+
+        for key in &all_keys {
+            let terms = self.index_terms(index, key)?;
+            let definition = self.get_indexes().get(index)?;
+            let mut index_keys = vec!(definition.keys_key());
+            index_keys.extend(terms.iter().map(|term| definition.term_key(term)));
+            self.add_to_indexes(key, index_keys)?;
+        }
+        Ok(all_keys.len())
+    }
+
+    /*
+        Return the terms of the current value of `key` in `index`
+     */
+    fn index_terms(&mut self, index: &str, key: &str) -> Result<Vec<String>> {
+        let bytes = match self.get_lww(key) {
+            Ok(lww) => lww.value,
+            Err(Error(ErrorKind::KeyDoesNotExist(_), _)) => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        Ok(self.get_indexes().get(index)?.extract(&bytes).unwrap_or_default())
+    }
+
+    /*
+        INDEX_QUERY <index> <term>
+     */
+    pub fn index_query(&mut self, tokens: &[&str]) -> Result<String> {
+        debug!("INDEX_QUERY: {:?}", tokens);
+        if tokens.len() < 2 {
+            bail!("Usage: INDEX_QUERY <index> <term>");
+        }
+        let keys = self.query_index(tokens[0], &tokens[1..].join(" "))?;
+        Ok(format_set(&keys.into_iter().collect::<Vec<Key>>()))
+    }
+
+    /*
+        INDEX_REBUILD <index> [<key>...]
+     */
+    pub fn index_rebuild(&mut self, tokens: &[&str]) -> Result<String> {
+        debug!("INDEX_REBUILD: {:?}", tokens);
+        let index = tokens.first().ok_or("Usage: INDEX_REBUILD <index> [<key>...]")?;
+        let count = self.rebuild_index(index, &tokens[1..])?;
+        Ok(format!("Re-indexed {} keys", count))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_derive::{Deserialize, Serialize};
+
+    use super::{IndexDefinition, IndexRegistry};
+    use crate::codec::{self, Codec};
+    use crate::expiry::with_expiry;
+
+    #[derive(Serialize, Deserialize)]
+    struct User {
+        email: String,
+        tags: Vec<String>,
+    }
+
+    #[test]
+    fn json_pointer_terms() {
+        let index = IndexDefinition::json_pointer("by_tag", "users/", "/tags");
+        assert_eq!(index.extract(br#"{"tags": ["admin", 7, null]}"#).expect("terms"),
+                   vec!("admin", "7"));
+        assert!(index.extract(br#"{"email": "a@b.c"}"#).expect("terms").is_empty());
+        assert!(index.extract(b"not json").is_err());
+    }
+
+    #[test]
+    fn typed_terms_ignore_expiry() {
+        let index = IndexDefinition::typed("by_email", "users/", |user: &User| vec!(user.email.clone()));
+        let user = User { email: "a@b.c".into(), tags: vec!() };
+        let bytes = codec::encode(&user, Codec::Bincode).expect("Could not encode");
+        assert_eq!(index.extract(&with_expiry(&bytes, 42)).expect("terms"), vec!("a@b.c"));
+    }
+
+    #[test]
+    fn updates_only_for_covered_keys() {
+        let mut registry = IndexRegistry::default();
+        registry.register(IndexDefinition::json_pointer("by_email", "users/", "/email"));
+        assert_eq!(registry.updates("users/1", br#"{"email": "a@b.c"}"#),
+                   vec!("ANNA_CLIENT|index|by_email|keys", "ANNA_CLIENT|index|by_email|terms|a@b.c"));
+        assert!(registry.updates("orders/1", br#"{"email": "a@b.c"}"#).is_empty());
+    }
+}
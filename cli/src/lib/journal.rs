@@ -0,0 +1,322 @@
+//! Journal module buffers writes on disk while the cluster is unreachable, so clients on
+//! intermittently connected nodes can keep writing.
+//!
+//! When a PUT fails because no servers have joined the cluster, or a request times out, the
+//! write is appended to the journal instead of failing. Journaled writes are replayed before
+//! the next write once the cluster may be reachable again, or when the journal is flushed.
+//! As lattice merges are commutative and idempotent, replaying a write that did reach the
+//! cluster before its request timed out, or replaying writes out of order, is safe.
+//!
+//! Each entry in the journal has a checksum. If the client stops while an entry is written, the
+//! journal ends at the entry, which is removed when the journal is next opened.
+//!
+//! Reads are not served from the journal, so a client does not read its own journaled writes.
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::errors::*;
+use crate::kvs_client::KVSClient;
+use crate::proto::anna::KeyTuple;
+
+// How long to wait after the cluster was found unreachable before trying to replay the journal
+// again. Writes made in the meantime are journaled without trying the cluster.
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/*
+    A write in the journal, with the namespace it was made in
+ */
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct JournalEntry {
+    namespace: Option<String>,
+    key: String,
+    lattice_type: i32,
+    payload: Vec<u8>,
+}
+
+/// `Journal` is a file of writes made while the cluster was unreachable, to be replayed
+pub struct Journal {
+    path: PathBuf,
+    pending: usize,
+    // when to next try to replay the journal, if the cluster was found unreachable
+    retry_at: Option<Instant>,
+}
+
+/// Return true if `error`, or any error that caused it, is because the cluster is unreachable
+pub fn is_unreachable(error: &Error) -> bool {
+    matches!(error.kind(), ErrorKind::ClusterUnreachable(_)) ||
+        error.1.next_error.as_ref()
+            .and_then(|cause| cause.downcast_ref::<Error>())
+            .is_some_and(is_unreachable)
+}
+
+/*
+    The checksum of an encoded journal entry, the first bytes of its SHA-256 digest
+ */
+fn checksum(encoded: &[u8]) -> [u8; 4] {
+    let digest = Sha256::digest(encoded);
+    [digest[0], digest[1], digest[2], digest[3]]
+}
+
+fn encode_entries(entries: &[JournalEntry]) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    for entry in entries {
+        let encoded = bincode::serialize(entry).chain_err(|| "Could not encode journal entry")?;
+        bytes.extend_from_slice(&(encoded.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&checksum(&encoded));
+        bytes.extend(encoded);
+    }
+    Ok(bytes)
+}
+
+/*
+    Decode the entries in `bytes`, and return them with the length of the bytes they were
+    decoded from. The journal ends at an entry that was only partly written, or whose checksum
+    does not match.
+ */
+fn decode_entries(mut bytes: &[u8]) -> Result<(Vec<JournalEntry>, usize)> {
+    let mut entries = Vec::new();
+    let mut length = 0;
+    while bytes.len() >= 8 {
+        let size = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+        let entry = match bytes.get(8..8 + size) {
+            Some(entry) if checksum(entry) == bytes[4..8] => entry,
+            _ => break,
+        };
+        entries.push(bincode::deserialize(entry).chain_err(|| "Could not decode journal entry")?);
+        bytes = &bytes[8 + size..];
+        length += 8 + size;
+    }
+    Ok((entries, length))
+}
+
+impl Journal {
+    /// Open the journal in the file `path`, which is created when the first write is journaled
+    pub fn open(path: &Path) -> Result<Journal> {
+        let mut journal = Journal { path: path.into(), pending: 0, retry_at: None };
+        let (entries, length) = journal.decode_file()?;
+        if (length as u64) < journal.file_length()? {
+            warn!("Removing a journal entry that was only partly written from '{}'", path.display());
+            journal.truncate(length)?;
+        }
+        journal.pending = entries.len();
+        Ok(journal)
+    }
+
+    /// Return the file the journal is kept in
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Return the number of writes waiting to be replayed
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+
+    /*
+        Return true if writes should be journaled without trying the cluster, as it was found
+        unreachable recently
+     */
+    pub(crate) fn is_offline(&self) -> bool {
+        self.pending > 0 && self.retry_at.is_some_and(|retry_at| Instant::now() < retry_at)
+    }
+
+    fn mark_offline(&mut self) {
+        self.retry_at = Some(Instant::now() + RETRY_INTERVAL);
+    }
+
+    fn append(&mut self, entry: &JournalEntry) -> Result<()> {
+        let length = self.file_length()?;
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)
+            .chain_err(|| format!("Could not open journal file '{}'", self.path.display()))?;
+        let written = file.write_all(&encode_entries(std::slice::from_ref(entry))?)
+            .and_then(|_| file.sync_data());
+        if let Err(e) = written {
+            // don't leave part of the entry for later entries to be appended after
+            let _ = file.set_len(length);
+            return Err(e).chain_err(|| format!("Could not write to journal file '{}'", self.path.display()));
+        }
+        self.pending += 1;
+        Ok(())
+    }
+
+    fn read_entries(&self) -> Result<Vec<JournalEntry>> {
+        Ok(self.decode_file()?.0)
+    }
+
+    fn decode_file(&self) -> Result<(Vec<JournalEntry>, usize)> {
+        if !self.path.exists() {
+            return Ok((Vec::new(), 0));
+        }
+        let mut bytes = Vec::new();
+        File::open(&self.path).and_then(|mut file| file.read_to_end(&mut bytes))
+            .chain_err(|| format!("Could not read journal file '{}'", self.path.display()))?;
+        decode_entries(&bytes)
+    }
+
+    fn file_length(&self) -> Result<u64> {
+        match fs::metadata(&self.path) {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e).chain_err(|| format!("Could not read journal file '{}'", self.path.display())),
+        }
+    }
+
+    /*
+        Remove everything after the first `length` bytes of the journal file
+     */
+    fn truncate(&self, length: usize) -> Result<()> {
+        OpenOptions::new().write(true).open(&self.path)
+            .and_then(|file| file.set_len(length as u64).and_then(|_| file.sync_data()))
+            .chain_err(|| format!("Could not truncate journal file '{}'", self.path.display()))
+    }
+
+    /*
+        Replace the contents of the journal with `entries`
+     */
+    fn rewrite(&mut self, entries: &[JournalEntry]) -> Result<()> {
+        let temporary = self.path.with_extension("tmp");
+        fs::write(&temporary, encode_entries(entries)?)
+            .and_then(|_| fs::rename(&temporary, &self.path))
+            .chain_err(|| format!("Could not rewrite journal file '{}'", self.path.display()))?;
+        self.pending = entries.len();
+        Ok(())
+    }
+}
+
+impl KVSClient {
+    /*
+        Write `tuple` to the cluster, or to the journal if one is configured and the cluster is
+        unreachable. Journaled writes are replayed first, if the cluster may be reachable again.
+     */
+    pub(crate) fn put_or_journal(&mut self, tuple: KeyTuple) -> Result<()> {
+        let (pending, offline) = match self.get_journal() {
+            Some(journal) => (journal.pending(), journal.is_offline()),
+            None => return self.put_tuple(tuple),
+        };
+
+        if !offline && pending > 0 {
+            match self.flush_journal() {
+                Err(ref e) if is_unreachable(e) => {}
+                Err(e) => return Err(e),
+                Ok(_) => {}
+            }
+        }
+
+        let offline = self.get_journal().is_some_and(Journal::is_offline);
+        let result = if offline {
+            Err(ErrorKind::ClusterUnreachable("writes are being journaled".into()).into())
+        } else {
+            self.put_tuple(tuple.clone())
+        };
+
+        match result {
+            Err(ref e) if is_unreachable(e) => {
+                info!("Journaling the write of key '{}': {}", tuple.key, e);
+                let entry = JournalEntry {
+                    namespace: self.get_namespace().map(|namespace| namespace.to_string()),
+                    key: tuple.key,
+                    lattice_type: tuple.lattice_type,
+                    payload: tuple.payload,
+                };
+                let journal = self.get_journal_mut().ok_or("No journal is configured")?;
+                journal.mark_offline();
+                journal.append(&entry)
+            }
+            result => result,
+        }
+    }
+
+    /// Replay the writes in the journal, returning the number replayed. If the cluster is
+    /// unreachable, the writes not yet replayed are kept in the journal.
+    pub fn flush_journal(&mut self) -> Result<usize> {
+        let entries = self.get_journal().ok_or("No journal is configured")?.read_entries()?;
+
+        for (replayed, entry) in entries.iter().enumerate() {
+            let tuple = KeyTuple {
+                key: entry.key.clone(),
+                lattice_type: entry.lattice_type,
+                payload: entry.payload.clone(),
+                ..Default::default()
+            };
+            if let Err(e) = self.put_tuple_in(entry.namespace.clone(), tuple) {
+                let journal = self.get_journal_mut().ok_or("No journal is configured")?;
+                journal.rewrite(&entries[replayed..])?;
+                if is_unreachable(&e) {
+                    journal.mark_offline();
+                }
+                return Err(e).chain_err(|| format!("Replayed {} of {} journaled writes",
+                                                   replayed, entries.len()));
+            }
+        }
+
+        if !entries.is_empty() {
+            info!("Replayed {} journaled writes", entries.len());
+            self.get_journal_mut().ok_or("No journal is configured")?.rewrite(&[])?;
+            // the writes replayed may have changed the schemas
+            self.reload_schemas();
+        }
+        Ok(entries.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    use super::{decode_entries, encode_entries, is_unreachable, Journal, JournalEntry};
+    use crate::errors::*;
+
+    fn entry(key: &str) -> JournalEntry {
+        JournalEntry { namespace: None, key: key.into(), lattice_type: 1, payload: vec!(1, 2, 3) }
+    }
+
+    #[test]
+    fn entries_round_trip() {
+        let entries = vec!(entry("a"), JournalEntry { namespace: Some("edge".into()), ..entry("b") });
+        let bytes = encode_entries(&entries).expect("Could not encode");
+        assert_eq!(decode_entries(&bytes).expect("Could not decode"), (entries, bytes.len()));
+    }
+
+    #[test]
+    fn partly_written_entries_end_the_journal() {
+        let bytes = encode_entries(&[entry("a"), entry("b")]).expect("Could not encode");
+        let first = encode_entries(&[entry("a")]).expect("Could not encode").len();
+        assert_eq!(decode_entries(&bytes[..bytes.len() - 2]).expect("Could not decode"),
+                   (vec!(entry("a")), first));
+
+        let mut corrupted = bytes.clone();
+        corrupted[first + 8] ^= 1;
+        assert_eq!(decode_entries(&corrupted).expect("Could not decode"), (vec!(entry("a")), first));
+    }
+
+    #[test]
+    fn entries_are_appended_after_a_partly_written_entry() {
+        let path = std::env::temp_dir().join("anna_journal_partly_written.bin");
+        let _ = std::fs::remove_file(&path);
+        let mut journal = Journal::open(&path).expect("Could not open");
+        journal.append(&entry("a")).expect("Could not append");
+        let torn = encode_entries(&[entry("b")]).expect("Could not encode");
+        let mut file = OpenOptions::new().append(true).open(&path).expect("Could not open");
+        file.write_all(&torn[..torn.len() - 2]).expect("Could not write");
+
+        let mut journal = Journal::open(&path).expect("Could not open");
+        journal.append(&entry("c")).expect("Could not append");
+        assert_eq!(journal.read_entries().expect("Could not read"), vec!(entry("a"), entry("c")));
+        assert_eq!(journal.pending(), 2);
+        std::fs::remove_file(&path).expect("Could not remove");
+    }
+
+    #[test]
+    fn unreachable_errors_are_found_in_the_chain() {
+        let cause = Error::from(ErrorKind::ClusterUnreachable("timed out".into()));
+        assert!(is_unreachable(&Error::with_chain(cause, "Could not find a worker thread")));
+        assert!(!is_unreachable(&Error::from("PUT failed")));
+    }
+}
@@ -0,0 +1,249 @@
+//! Json path module queries and updates fields of JSON values stored in LWW lattices, so that
+//! a field can be read or changed without handling the whole value.
+//!
+//! Paths use the syntax shared by jq and JSONPath: an optional leading `$`, then `.field`,
+//! `["field"]` or `[index]` for each step, such as `.orders[0].status` or `$["user id"]`.
+//! Negative indexes count from the end of an array. The path `.` (or `$`) is the whole value.
+//!
+//! Updates are a read-modify-write of the whole value, as `anna` has no conditional writes, so
+//! an update can be lost to a concurrent write. The value is read again just before it is
+//! written, and the update abandoned if it has changed since it was first read, and read again
+//! after it is written, to report when a concurrent write has overtaken it. An update journaled
+//! while the cluster is unreachable cannot be checked after it is written. A value written with a
+//! TTL keeps its expiry time when it is updated.
+use log::debug;
+use serde_json::Value;
+
+use crate::envelope::tag_plain;
+use crate::errors::*;
+use crate::expiry::with_expiry;
+use crate::journal::{is_unreachable, Journal};
+use crate::kvs_client::{default_if_missing, KVSClient};
+
+/// A step of a `JsonPath`
+#[derive(Debug, PartialEq)]
+pub enum Step {
+    /// The field of an object with the given name
+    Field(String),
+    /// The element of an array at the given index, from the end if negative
+    Index(i64),
+}
+
+/// `JsonPath` is the location of a field within a JSON value
+#[derive(Debug, PartialEq)]
+pub struct JsonPath {
+    steps: Vec<Step>,
+}
+
+impl JsonPath {
+    /// Parse a path such as `.orders[0].status`
+    pub fn parse(path: &str) -> Result<JsonPath> {
+        let invalid = || format!("'{}' is not a valid path", path);
+        let mut rest = path.strip_prefix('$').unwrap_or(path);
+        if rest == "." {
+            rest = "";
+        }
+
+        let mut steps = Vec::new();
+        while !rest.is_empty() {
+            if let Some(field) = rest.strip_prefix('.') {
+                let end = field.find(['.', '[']).unwrap_or(field.len());
+                if end == 0 {
+                    bail!(invalid());
+                }
+                steps.push(Step::Field(field[..end].to_string()));
+                rest = &field[end..];
+            } else if let Some(bracket) = rest.strip_prefix('[') {
+                let end = bracket.find(']').ok_or_else(invalid)?;
+                let inside = &bracket[..end];
+                let quoted = inside.len() >= 2 &&
+                    ((inside.starts_with('"') && inside.ends_with('"')) ||
+                        (inside.starts_with('\'') && inside.ends_with('\'')));
+                steps.push(if quoted {
+                    Step::Field(inside[1..inside.len() - 1].to_string())
+                } else {
+                    Step::Index(inside.parse().chain_err(invalid)?)
+                });
+                rest = &bracket[end + 1..];
+            } else {
+                bail!(invalid());
+            }
+        }
+        Ok(JsonPath { steps })
+    }
+
+    /// Return the field of `value` at this path, if it has one
+    pub fn get<'a>(&self, value: &'a Value) -> Option<&'a Value> {
+        self.steps.iter().try_fold(value, |value, step| match (step, value) {
+            (Step::Field(name), Value::Object(fields)) => fields.get(name),
+            (Step::Index(index), Value::Array(elements)) =>
+                position(*index, elements.len()).and_then(|index| elements.get(index)),
+            _ => None,
+        })
+    }
+
+    /// Set the field of `value` at this path to `field`. Missing fields of objects are added,
+    /// with any objects on the path to them, but arrays are not extended.
+    pub fn set(&self, value: &mut Value, field: Value) -> Result<()> {
+        let mut current = value;
+        for step in &self.steps {
+            if current.is_null() {
+                *current = Value::Object(serde_json::Map::new());
+            }
+            current = match (step, current) {
+                (Step::Field(name), Value::Object(fields)) =>
+                    fields.entry(name.clone()).or_insert(Value::Null),
+                (Step::Index(index), Value::Array(elements)) => {
+                    let length = elements.len();
+                    position(*index, length).and_then(move |position| elements.get_mut(position))
+                        .ok_or_else(|| format!("Index {} is out of bounds of an array of {}",
+                                               index, length))?
+                }
+                (Step::Field(name), _) => bail!("Cannot set field '{}' of a value that is not an object", name),
+                (Step::Index(index), _) => bail!("Cannot set index {} of a value that is not an array", index),
+            };
+        }
+        *current = field;
+        Ok(())
+    }
+}
+
+/*
+    Return the position in an array of `length` of `index`, which counts from the end if negative
+ */
+fn position(index: i64, length: usize) -> Option<usize> {
+    let position = if index < 0 { length as i64 + index } else { index };
+    if position >= 0 && (position as usize) < length { Some(position as usize) } else { None }
+}
+
+fn parse_json(key: &str, bytes: &[u8]) -> Result<Value> {
+    serde_json::from_slice(bytes).chain_err(|| format!("The value of key '{}' is not JSON", key))
+}
+
+impl KVSClient {
+    /*
+        GET <key> <path>
+     */
+    pub(crate) fn get_path(&mut self, key: &str, path: &str) -> Result<String> {
+        let path = JsonPath::parse(path)?;
+        let value = parse_json(key, &self.get_lww(key)?.value)?;
+        let field = path.get(&value).unwrap_or(&Value::Null);
+        serde_json::to_string_pretty(field).chain_err(|| "Could not format JSON")
+    }
+
+    /*
+        PATCH <key> <path> <value>
+
+        The field at the path is set to the JSON value, in the value of the key or in a new object
+        if the key does not exist
+     */
+    pub fn patch(&mut self, tokens: &[&str]) -> Result<String> {
+        debug!("PATCH: {:?}", tokens);
+        if tokens.len() < 3 {
+            bail!("Usage: PATCH <key> <path> <value>");
+        }
+        let (key, path) = (tokens[0], JsonPath::parse(tokens[1])?);
+        let field: Value = serde_json::from_str(&tokens[2..].join(" "))
+            .chain_err(|| format!("'{}' is not valid JSON", tokens[2..].join(" ")))?;
+
+        let read = default_if_missing(self.get_lww_with_expiry(key).map(Some))?;
+        let read_timestamp = read.as_ref().map(|(lww, _)| lww.timestamp);
+        let (mut value, expires_at) = match read {
+            Some((lww, expires_at)) => (parse_json(key, &lww.value)?, expires_at),
+            None => (Value::Null, None),
+        };
+        path.set(&mut value, field)?;
+        let bytes = serde_json::to_vec(&value).chain_err(|| "Could not serialize JSON")?;
+        self.check_schema(key, &bytes)?;
+
+        let current = default_if_missing(self.get_lww(key).map(|lww| Some(lww.timestamp)))?;
+        if current != read_timestamp {
+            bail!("Key '{}' was written concurrently while it was patched, so it was not patched",
+                  key);
+        }
+        let timestamp = self.next_timestamp();
+        self.put_lww_reporting_at(key, stored_patch(&bytes, expires_at), timestamp)?;
+        self.register_key(key)?;
+        if self.get_journal().is_some_and(Journal::is_offline) {
+            return Ok("Success! The patch was journaled, so was not checked for concurrent writes".into());
+        }
+
+        let written = match default_if_missing(self.get_lww(key).map(|lww| Some(lww.timestamp))) {
+            Ok(written) => written,
+            Err(ref e) if is_unreachable(e) =>
+                return Ok("Success! The patch could not be checked for concurrent writes".into()),
+            Err(e) => return Err(e),
+        };
+        match written {
+            Some(written) if written == timestamp || Some(written) == read_timestamp => {}
+            Some(written) => bail!("The patch of key '{}' was overtaken by a concurrent write with \
+                                    timestamp {}", key, written),
+            None => bail!("The patch of key '{}' was overtaken by a concurrent delete", key),
+        }
+        Ok("Success!".into())
+    }
+}
+
+/*
+    The bytes to store for the patched `value` of a key, which keeps the expiry time of the value
+    it patched, if any
+ */
+fn stored_patch(value: &[u8], expires_at: Option<u64>) -> Vec<u8> {
+    match expires_at {
+        Some(expires_at) => with_expiry(&tag_plain(value), expires_at),
+        None => tag_plain(value),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::{stored_patch, JsonPath, Step};
+    use crate::envelope::{strip_plain, tag_plain};
+    use crate::expiry::split_expiry;
+
+    #[test]
+    fn paths_are_parsed() {
+        let path = JsonPath::parse(r#"$.orders[-1]["ship to"].city"#).expect("path");
+        assert_eq!(path.steps, vec!(Step::Field("orders".into()), Step::Index(-1),
+                                    Step::Field("ship to".into()), Step::Field("city".into())));
+        assert!(JsonPath::parse(".").expect("root").steps.is_empty());
+        assert!(JsonPath::parse("orders").is_err());
+        assert!(JsonPath::parse(".orders[first]").is_err());
+        assert!(JsonPath::parse(".orders..id").is_err());
+    }
+
+    #[test]
+    fn fields_are_found() {
+        let value = json!({"orders": [{"id": 1}, {"id": 2}]});
+        let get = |path| JsonPath::parse(path).expect("path").get(&value).cloned();
+        assert_eq!(get(".orders[0].id"), Some(json!(1)));
+        assert_eq!(get(".orders[-1]"), Some(json!({"id": 2})));
+        assert_eq!(get(".orders[2]"), None);
+        assert_eq!(get(".orders.id"), None);
+        assert_eq!(get("."), Some(value.clone()));
+    }
+
+    #[test]
+    fn fields_are_set() {
+        let mut value = json!({"orders": [{"id": 1}]});
+        JsonPath::parse(".orders[0].status").expect("path").set(&mut value, json!("shipped")).expect("set");
+        JsonPath::parse(".owner.name").expect("path").set(&mut value, json!("anna")).expect("set");
+        assert_eq!(value, json!({"orders": [{"id": 1, "status": "shipped"}], "owner": {"name": "anna"}}));
+        assert!(JsonPath::parse(".orders[1]").expect("path").set(&mut value, json!(2)).is_err());
+        assert!(JsonPath::parse(".orders.id").expect("path").set(&mut value, json!(2)).is_err());
+
+        let mut missing = serde_json::Value::Null;
+        JsonPath::parse(".count").expect("path").set(&mut missing, json!(1)).expect("set");
+        assert_eq!(missing, json!({"count": 1}));
+    }
+
+    #[test]
+    fn patches_keep_the_expiry_time() {
+        let stored = stored_patch(b"{}", Some(1_600_000_000_000));
+        assert_eq!(split_expiry(&stored), (Some(1_600_000_000_000), &tag_plain(b"{}")[..]));
+        assert_eq!(strip_plain(split_expiry(&stored).1), b"{}");
+        assert_eq!(stored_patch(b"{}", None), tag_plain(b"{}"));
+    }
+}
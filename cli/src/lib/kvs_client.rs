@@ -1,36 +1,64 @@
-// #include "anna.pb.h"
-// #include "common.hpp"
-// #include "requests.hpp"
-
-use std::time::{SystemTime, UNIX_EPOCH, Duration};
-use zmq::Context;
-use log::{info, debug};
+use std::time::{SystemTime, UNIX_EPOCH, Duration, Instant};
+use std::path::Path;
+use zmq::{Context, Socket};
+use log::{info, debug, warn};
+use prost::Message;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
 
+use crate::codec::{self, Codec};
 use crate::config::Config;
+use crate::display::{format_set, format_ordered_set, format_tuple};
+use crate::catalog::Catalog;
+use crate::chunk::DEFAULT_CHUNK_THRESHOLD;
+use crate::compression::{decompress, Compression, WriteReport, DEFAULT_COMPRESSION_THRESHOLD};
+use crate::encryption::Keyring;
+use crate::envelope::{strip_plain, tag_plain};
+use crate::journal::{is_unreachable, Journal};
+use crate::ramp::decode_record;
+use crate::index::IndexRegistry;
+use crate::renderers::{Renderer, RendererRegistry};
+use crate::schema::{Schema, SchemaRegistry};
+use crate::errors::*;
+use crate::session::Session;
+use crate::expiry::{is_expired, now_ms, split_expiry};
+use crate::tombstone::{is_tombstone, is_tombstoned};
 
 pub type Address = String;
 pub type Key = String;
 pub type TimePoint = std::time::SystemTime;
 
 use crate::threads::{UserRoutingThread, UserThread};
-use crate::proto::anna::KeyTuple;
+use crate::proto::anna::{KeyTuple, KeyRequest, KeyResponse, KeyAddressRequest, KeyAddressResponse,
+                         RequestType, LatticeType, AnnaError, LwwValue, SetValue, SingleKeyCausalValue,
+                         MultiKeyCausalValue, PriorityValue};
+use crate::proto::shared::KeyVersion;
 use std::collections::{HashMap, HashSet};
 use std::hash::{Hash, Hasher};
 use std::collections::hash_map::DefaultHasher;
 use rand::{Rng, SeedableRng};
 use rand_pcg::Pcg64;
 
-struct PendingRequest {
-    tp: TimePoint,
-    worker_addr: Address,
-    // request:    KeyRequest
-}
+// The number of times a request is re-issued when a server tells us our address cache is stale
+const MAX_REQUEST_ATTEMPTS: usize = 3;
+
+/// The first part of the keys `anna` stores its own metadata under (`kMetadataIdentifier` in
+/// `common.hpp`), which clients must not write to
+pub const METADATA_IDENTIFIER: &str = "ANNA_METADATA";
+/// Separates the parts of metadata keys (`kMetadataDelimiter` in `common.hpp`)
+pub const METADATA_DELIMITER: char = '|';
+
+/// The prefix of the reserved keys the client stores its own metadata under, such as the
+/// catalog of deleted keys
+pub const CLIENT_METADATA_PREFIX: &str = "ANNA_CLIENT|";
 
 pub struct KVSClient {
     // the set of routing addresses outside the cluster
     routing_threads: Vec<UserRoutingThread>,
     // the current request id
     rid: usize,
+    // the sequence number of the last unique id generated
+    sequence: u64,
     // the IP and port functions for this thread
     ut: UserThread,
     seed: u64,
@@ -38,81 +66,73 @@ pub struct KVSClient {
     rng: Pcg64,
     // the ZMQ context we use to create sockets
     context: Context,
+    // cache for opened sockets, organized by the address they are connected to
+    socket_cache: HashMap<Address, Socket>,
+    // ZMQ receiving socket for responses from the routing tier
+    key_address_puller: Socket,
+    // ZMQ receiving socket for responses from the KVS
+    response_puller: Socket,
     // cache for retrieved worker addresses organized by key
     key_address_cache: HashMap<Key, HashSet<Address>>,
-    // GC timeout
+    // request timeout in ms
     timeout: usize,
+    // the causal session state of this client
+    session: Session,
+    // the file the session is persisted to, if any
+    session_file: Option<String>,
+    // renderers used to parse and display the values of keys in commands
+    renderers: RendererRegistry,
+    // secondary indexes maintained on writes
+    indexes: IndexRegistry,
+    // the catalog keys written are registered in, if enabled
+    catalog: Option<Catalog>,
+    // the namespace all keys are prefixed with, if any
+    namespace: Option<String>,
+    // LWW values longer than this, in bytes, are stored in chunks
+    chunk_threshold: usize,
+    // the algorithm LWW values are compressed with, if enabled
+    compression: Option<Compression>,
+    // LWW values longer than this, in bytes, are compressed
+    compression_threshold: usize,
+    // the keys values are encrypted with, if encryption is enabled
+    keyring: Option<Keyring>,
+    // the journal writes are buffered in while the cluster is unreachable, if enabled
+    journal: Option<Journal>,
+    // the patterns of the keys whose history of values is kept
+    versioned: Vec<String>,
+    // the schemas of the values of keys, loaded when first used
+    schemas: Option<SchemaRegistry>,
+    // the registry used while the schemas cannot be loaded, which has none
+    no_schemas: SchemaRegistry,
 }
 
-//     // cache for opened sockets
-//     socket_cache: SocketCache,
-//
-//     // ZMQ receiving sockets
-//     key_address_puller: zmq::socket_t,
-//     response_puller: zmq::socket_t,
-//
-//     pollitems: Vec<zmq::pollitem_t>,
-//
-//     // keeps track of pending requests due to missing worker address
-//     pending_request_map: Map<Key, (TimePoint, Vec<KeyRequest>)>,
-//
-//     // keeps track of pending get responses
-//     pending_get_response_map: Map<Key, PendingRequest>,
-//
-//     // keeps track of pending put responses
-//     pending_put_response_map: Map<Key, Map<string, PendingRequest>>
-
-
-//     /*
-//         addrs A vector of routing addresses.
-//         routing_thread_count The number of thread sone ach routing node
-//         ip My node's IP address
-//         tid My client's thread ID
-//         timeout Length of request timeouts in ms
-//     */
-// //       log_(spdlog::basic_logger_mt("client_log", "client_log.txt", true)),
-//     pub fn new(
-//             routing_threads: Vec<UserRoutingThread>,
-//             ip: String,
-//             tid: Option<usize>,
-//             timeout: Option<usize>) -> Self {
-//
-//         let key_address_puller = zmq::socket_t(context, ZMQ_PULL);
-//         let response_puller = zmq::socket_t(context, ZMQ_PULL);
-//
-//         pollitems = {
-// //         {static_cast<void*>(key_address_puller_), 0, ZMQ_POLLIN, 0},
-// //         {static_cast<void*>(response_puller_), 0, ZMQ_POLLIN, 0},
-//         };
-//
-
-//
-//         let client = KVSClient {
-//             ut,
-//             context,
-//             socket_cache: SocketCache(&context_, ZMQ_PUSH),
-//             key_address_puller,
-//             response_puller,
-//             routing_threads,
-//             rid: 0,
-//             timeout: timeout.some_or(10000),
-//             pending_request_map: (),
-//             pending_get_response_map: (),
-//             pollitems,
-//             seed,
-//             key_address_cache: (),
-//             pending_put_response_map: ()
-//         };
-//
-//         // bind the two sockets we listen on
-//         key_address_puller.bind(ut.key_address_bind_address());
-//         response_puller.bind(ut.response_bind_address());
-//
-//         client
-//     }
-
 impl KVSClient {
-    pub fn new(config: &Config, tid: Option<usize>) -> Self {
+    /*
+        config The configuration to read routing addresses and this node's IP from
+        tid My client's thread ID
+        session_file A file to load the causal session from, and save it back to
+        namespace A namespace to prefix all keys with, so that clients in different namespaces
+                  can share a cluster without their keys colliding
+    */
+    pub fn new(config: &Config, tid: Option<usize>, session_file: Option<&str>,
+               namespace: Option<&str>) -> Result<Self> {
+        if let Some(namespace) = namespace {
+            check_namespace(namespace)?;
+        }
+        let (compression, compression_threshold) = match config.get_compression() {
+            Some((name, threshold)) => (Some(Compression::from_name(name)?),
+                                        threshold.unwrap_or(DEFAULT_COMPRESSION_THRESHOLD)),
+            None => (None, DEFAULT_COMPRESSION_THRESHOLD),
+        };
+        let keyring = match config.get_keyring_file() {
+            Some(filename) => Some(Keyring::read(filename)?),
+            None => None,
+        };
+        let journal = match config.get_journal_file() {
+            Some(filename) => Some(Journal::open(Path::new(filename))?),
+            None => None,
+        };
+
         let tid = tid.unwrap_or(0);
         let thread_count = config.get_routing_thread_count();
         let routing_ips = config.get_routing_ips();
@@ -127,29 +147,53 @@ impl KVSClient {
         info!("Random seed is {}.", seed);
         let rng = rand_pcg::Pcg64::seed_from_u64(seed);
 
-        // socket_cache_(SocketCache(&context_, ZMQ_PUSH)),
-        // key_address_puller_(zmq::socket_t(context_, ZMQ_PULL)),
-        // response_puller_(zmq::socket_t(context_, ZMQ_PULL)),
-        //
-        // // bind the two sockets we listen on
-        // key_address_puller_.bind(ut_.key_address_bind_address());
-        // response_puller_.bind(ut_.response_bind_address());
-        //
-        // pollitems_ = {
-        // {static_cast<void*>(key_address_puller_), 0, ZMQ_POLLIN, 0},
-        // {static_cast<void*>(response_puller_), 0, ZMQ_POLLIN, 0},
-        // };
-
-        KVSClient {
+        let ut = UserThread::new(config.get_user_ip(), tid);
+        let context = zmq::Context::new();
+
+        // bind the two sockets we listen on
+        let key_address_puller = context.socket(zmq::PULL)
+            .chain_err(|| "Could not create key address socket")?;
+        key_address_puller.bind(&ut.key_address_bind_address())
+            .chain_err(|| format!("Could not bind to '{}'", ut.key_address_bind_address()))?;
+        let response_puller = context.socket(zmq::PULL)
+            .chain_err(|| "Could not create response socket")?;
+        response_puller.bind(&ut.response_bind_address())
+            .chain_err(|| format!("Could not bind to '{}'", ut.response_bind_address()))?;
+
+        let session = match session_file {
+            Some(filename) if Path::new(filename).exists() => Session::load(filename)?,
+            _ => Session::new(&format!("{}:{}_{}", ut.ip(), ut.tid(), seed)),
+        };
+        info!("Client id is {}.", session.client_id());
+
+        Ok(KVSClient {
             routing_threads,
             rid: 0,
-            ut: UserThread::new(config.get_user_ip(), tid),
+            sequence: 0,
+            ut,
             seed,
             rng,
-            context: zmq::Context::new(),
+            context,
+            socket_cache: HashMap::new(),
+            key_address_puller,
+            response_puller,
             key_address_cache: HashMap::new(),
             timeout: 10000,
-        }
+            session,
+            session_file: session_file.map(|filename| filename.to_string()),
+            renderers: RendererRegistry::from_config(config)?,
+            indexes: IndexRegistry::from_config(config),
+            catalog: if config.catalog_enabled() { Some(Catalog::default()) } else { None },
+            namespace: namespace.map(|namespace| namespace.to_string()),
+            chunk_threshold: DEFAULT_CHUNK_THRESHOLD,
+            compression,
+            compression_threshold,
+            keyring,
+            journal,
+            versioned: config.get_versioned_patterns().to_vec(),
+            schemas: None,
+            no_schemas: SchemaRegistry::default(),
+        })
     }
 
     /*
@@ -195,6 +239,192 @@ impl KVSClient {
         self.seed
     }
 
+    /*
+        Return the causal session state of this client.
+    */
+    pub fn get_session(&self) -> &Session {
+        &self.session
+    }
+
+    /*
+        Return the registry of renderers used to parse and display values in commands.
+    */
+    pub fn get_renderers(&mut self) -> &mut RendererRegistry {
+        &mut self.renderers
+    }
+
+    /*
+        Return the registry of secondary indexes maintained on writes.
+    */
+    pub fn get_indexes(&mut self) -> &mut IndexRegistry {
+        &mut self.indexes
+    }
+
+    /*
+        Return the namespace all keys are prefixed with, if any.
+    */
+    pub fn get_namespace(&self) -> Option<&str> {
+        self.namespace.as_deref()
+    }
+
+    /*
+        Return the length, in bytes, above which LWW values are stored in chunks.
+    */
+    pub fn get_chunk_threshold(&self) -> usize {
+        self.chunk_threshold
+    }
+
+    /*
+        Set the length, in bytes, above which LWW values are stored in chunks.
+    */
+    pub fn set_chunk_threshold(&mut self, threshold: usize) {
+        self.chunk_threshold = threshold;
+    }
+
+    /*
+        Return the algorithm LWW values are compressed with, if compression is enabled.
+    */
+    pub fn get_compression(&self) -> Option<Compression> {
+        self.compression
+    }
+
+    /*
+        Set the algorithm LWW values are compressed with, or disable compression.
+    */
+    pub fn set_compression(&mut self, compression: Option<Compression>) {
+        self.compression = compression;
+    }
+
+    /*
+        Return the length, in bytes, above which LWW values are compressed.
+    */
+    pub fn get_compression_threshold(&self) -> usize {
+        self.compression_threshold
+    }
+
+    /*
+        Set the length, in bytes, above which LWW values are compressed.
+    */
+    pub fn set_compression_threshold(&mut self, threshold: usize) {
+        self.compression_threshold = threshold;
+    }
+
+    /*
+        Return the keyring values are encrypted with, if encryption is enabled.
+    */
+    pub fn get_keyring(&self) -> Option<&Keyring> {
+        self.keyring.as_ref()
+    }
+
+    /*
+        Set the keyring values are encrypted with, or disable encryption.
+    */
+    pub fn set_keyring(&mut self, keyring: Option<Keyring>) {
+        self.keyring = keyring;
+    }
+
+    /*
+        Return the journal writes are buffered in while the cluster is unreachable, if enabled.
+    */
+    pub fn get_journal(&self) -> Option<&Journal> {
+        self.journal.as_ref()
+    }
+
+    /*
+        Return the journal writes are buffered in, to modify it.
+    */
+    pub(crate) fn get_journal_mut(&mut self) -> Option<&mut Journal> {
+        self.journal.as_mut()
+    }
+
+    /*
+        Set the journal writes are buffered in while the cluster is unreachable, or disable it.
+    */
+    pub fn set_journal(&mut self, journal: Option<Journal>) {
+        self.journal = journal;
+    }
+
+    /*
+        Return the patterns of the keys whose history of values is kept.
+    */
+    pub fn get_versioned_patterns(&self) -> &[String] {
+        &self.versioned
+    }
+
+    /*
+        Return the patterns of the keys whose history of values is kept, to modify them.
+    */
+    pub(crate) fn get_versioned_patterns_mut(&mut self) -> &mut Vec<String> {
+        &mut self.versioned
+    }
+
+    /*
+        Return the registry of the schemas of the values of keys, loading it on first use. If
+        the cluster is unreachable, no schemas are used and the registry is loaded again when
+        next used. While writes are being journaled it is not loaded, so that writes can still
+        be journaled without each waiting for the load to time out.
+    */
+    pub fn get_schemas(&mut self) -> Result<&SchemaRegistry> {
+        if self.schemas.is_none() {
+            if self.get_journal().is_some_and(Journal::is_offline) {
+                debug!("Values are not checked against schemas while writes are being journaled");
+                return Ok(&self.no_schemas);
+            }
+            match SchemaRegistry::load(self) {
+                Ok(schemas) => self.schemas = Some(schemas),
+                Err(ref e) if is_unreachable(e) => {
+                    warn!("Values are not checked against schemas, as they could not be loaded: {}", e);
+                    return Ok(&self.no_schemas);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        self.schemas.as_ref().ok_or_else(|| "Schemas were not loaded".into())
+    }
+
+    /*
+        Discard the registry of schemas, so it is loaded again when next used.
+    */
+    pub fn reload_schemas(&mut self) {
+        self.schemas = None;
+    }
+
+    /*
+        Return the renderer for `key`: its schema if it has one, or the renderer registered
+        for it otherwise.
+    */
+    pub fn renderer_for(&mut self, key: &str) -> Result<&dyn Renderer> {
+        self.get_schemas()?;
+        Ok(match self.schemas.as_ref().and_then(|schemas| schemas.schema_for(key)) {
+            Some(schema) => schema,
+            None => self.renderers.renderer_for(key),
+        })
+    }
+
+    /*
+        Return the catalog keys written are registered in, if it is enabled.
+    */
+    pub fn get_catalog(&mut self) -> Option<&mut Catalog> {
+        self.catalog.as_mut()
+    }
+
+    /*
+        Enable or disable registering the keys written in the catalog.
+    */
+    pub fn set_catalog_enabled(&mut self, enabled: bool) {
+        self.catalog = if enabled { Some(Catalog::default()) } else { None };
+    }
+
+    /*
+        Write the causal session state back to the session file, if one was given.
+    */
+    pub fn save_session(&self) -> Result<()> {
+        match &self.session_file {
+            Some(filename) => self.session.save(filename),
+            None => Ok(()),
+        }
+    }
+
     /*
       Generates a unique request ID. usize will overflow and start counting from
       zero again when MAX_INT is reached.
@@ -204,6 +434,18 @@ impl KVSClient {
         format!("{}:{}_{}", self.ut.ip(), self.ut.tid(), self.rid)
     }
 
+    /// Generate a timestamp for an LWW write by this client
+    pub fn next_timestamp(&self) -> u64 {
+        generate_timestamp(self.ut.tid())
+    }
+
+    /// Generate a unique id, made from this client's id, the time and a sequence number, for
+    /// tagging the members written to SET lattices
+    pub fn generate_unique_id(&mut self) -> String {
+        self.sequence += 1;
+        format!("{}:{}:{}", self.session.client_id(), generate_timestamp(0), self.sequence)
+    }
+
     /*
       Returns one random routing thread's key address connection address. If the
       client is running outside of the cluster (ie, it is querying the ELB),
@@ -212,171 +454,709 @@ impl KVSClient {
     fn get_routing_thread(&mut self) -> Address {
         // random index into threads array - from 0 upto but not including routing_threads.len()
         self.routing_threads[self.rng.gen_range(0..self.routing_threads.len())]
-            .key_address_connect_address()
+            .key_address_request_connect_address()
+    }
+
+    /// Issue a PUT request for `key` with a serialized lattice `payload` of type `lattice_type`
+    pub fn put_lattice(&mut self, key: &str, lattice_type: LatticeType, payload: Vec<u8>) -> Result<()> {
+        let tuple = KeyTuple {
+            key: key.into(),
+            lattice_type: lattice_type as i32,
+            payload,
+            ..Default::default()
+        };
+        self.put_or_journal(tuple)
+    }
+
+    /*
+        Issue a PUT request for `tuple`
+     */
+    pub(crate) fn put_tuple(&mut self, tuple: KeyTuple) -> Result<()> {
+        let key = tuple.key.clone();
+        let response = self.try_request(RequestType::Put, tuple)?;
+        if response.error() != AnnaError::NoError {
+            bail!("PUT of key '{}' failed with error {:?}", key, response.error());
+        }
+        Ok(())
+    }
+
+    /*
+        Issue a PUT request for `tuple`, with its key in `namespace` rather than this client's
+     */
+    pub(crate) fn put_tuple_in(&mut self, namespace: Option<String>, tuple: KeyTuple) -> Result<()> {
+        let own_namespace = std::mem::replace(&mut self.namespace, namespace);
+        let result = self.put_tuple(tuple);
+        self.namespace = own_namespace;
+        result
+    }
+
+    /// Issue a GET request for `key` and return the `KeyTuple` holding the serialized lattice
+    pub fn get_lattice(&mut self, key: &str) -> Result<KeyTuple> {
+        let tuple = KeyTuple {
+            key: key.into(),
+            ..Default::default()
+        };
+
+        let response = self.try_request(RequestType::Get, tuple)?;
+        match response.error() {
+            AnnaError::NoError => Ok(response),
+            AnnaError::KeyDne => bail!(ErrorKind::KeyDoesNotExist(key.into())),
+            error => bail!("GET of key '{}' failed with error {:?}", key, error),
+        }
+    }
+
+    /// Issue GET requests for all of `keys`, batched into one request per worker thread, and
+    /// return the `KeyTuple` of each key that exists. Keys whose worker has changed are
+    /// re-requested individually.
+    pub fn get_lattices(&mut self, keys: &[Key]) -> Result<HashMap<Key, KeyTuple>> {
+        let mut batches: HashMap<Address, Vec<KeyTuple>> = HashMap::new();
+        let mut user_keys = HashMap::new();
+        for key in keys {
+            let namespaced = namespaced(self.namespace.as_deref(), key)?;
+            let worker = self.get_worker_thread(&namespaced)
+                .chain_err(|| format!("Could not find a worker thread for key '{}'", key))?;
+            batches.entry(worker).or_default().push(KeyTuple {
+                key: namespaced.clone(),
+                address_cache_size: self.key_address_cache.get(&namespaced)
+                    .map_or(0, |addresses| addresses.len() as u32),
+                ..Default::default()
+            });
+            user_keys.insert(namespaced, key.clone());
+        }
+
+        let mut tuples = HashMap::new();
+        let mut retries = Vec::new();
+        for (worker, batch) in batches {
+            let request = KeyRequest {
+                r#type: RequestType::Get as i32,
+                tuples: batch,
+                response_address: self.ut.response_connect_address(),
+                request_id: self.get_request_id(),
+            };
+            self.send_request(&worker, &request)?;
+            let response: KeyResponse = receive_response(&self.response_puller, self.timeout,
+                                                         &request.request_id,
+                                                         |r: &KeyResponse| &r.response_id)
+                .inspect_err(|_| self.invalidate_cache_for_worker(&worker))?;
+
+            for mut tuple in response.tuples {
+                let key = user_keys.get(&tuple.key).cloned()
+                    .ok_or_else(|| format!("Response contained unrequested key '{}'", tuple.key))?;
+                if tuple.invalidate || tuple.error() == AnnaError::WrongThread {
+                    self.invalidate_cache_for_key(&tuple.key, &tuple);
+                }
+                match tuple.error() {
+                    AnnaError::NoError => {
+                        tuple.key = key.clone();
+                        tuples.insert(key, tuple);
+                    }
+                    AnnaError::KeyDne => {}
+                    AnnaError::WrongThread => retries.push(key),
+                    error => bail!("GET of key '{}' failed with error {:?}", key, error),
+                }
+            }
+        }
+
+        for key in retries {
+            match self.get_lattice(&key) {
+                Ok(tuple) => {
+                    tuples.insert(key, tuple);
+                }
+                Err(Error(ErrorKind::KeyDoesNotExist(_), _)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(tuples)
+    }
+
+    /// Write `value` to the LWW lattice of `key`, returning the timestamp it was written with.
+    /// The key is added to the secondary indexes that cover it, large values are compressed if
+    /// compression is enabled, values are encrypted if encryption is enabled, and large values
+    /// are stored in chunks.
+    pub fn put_lww(&mut self, key: &str, value: Vec<u8>) -> Result<u64> {
+        Ok(self.put_lww_reporting(key, value)?.timestamp)
+    }
+
+    /// Write `value` to the LWW lattice of `key` as `put_lww` does, returning the timestamp it
+    /// was written with and the length of the value before and after compression
+    pub fn put_lww_reporting(&mut self, key: &str, value: Vec<u8>) -> Result<WriteReport> {
+        let timestamp = self.next_timestamp();
+        self.put_lww_reporting_at(key, tag_plain(&value), timestamp)
+    }
+
+    /// Write the stored `value` to the LWW lattice of `key` as `put_lww_reporting` does, with the
+    /// given `timestamp`. Values written by users must be tagged with `envelope::tag_plain`. If
+    /// `key` is versioned, the value is also written as a new version of it.
+    pub fn put_lww_reporting_at(&mut self, key: &str, value: Vec<u8>, timestamp: u64)
+                                -> Result<WriteReport> {
+        if self.is_versioned(key) {
+            self.put_version(key, value.clone(), timestamp)?;
+        }
+        self.put_lww_unversioned_at(key, value, timestamp)
+    }
+
+    /*
+        Write `value` to the LWW lattice of `key` as `put_lww_reporting_at` does, without
+        writing a version of it
+     */
+    pub(crate) fn put_lww_unversioned_at(&mut self, key: &str, value: Vec<u8>, timestamp: u64)
+                                         -> Result<WriteReport> {
+        let index_keys = self.indexes.updates(key, &value);
+        let report = self.put_lww_encoded_at(key, value, timestamp)?;
+        self.add_to_indexes(key, index_keys)?;
+        Ok(report)
+    }
+
+    /*
+        Compress, encrypt and chunk `value` as needed, and write it to the LWW lattice of `key`
+        with the given `timestamp`, without updating any index
+     */
+    pub(crate) fn put_lww_encoded_at(&mut self, key: &str, value: Vec<u8>, timestamp: u64)
+                                     -> Result<WriteReport> {
+        let length = value.len();
+        let value = self.compress_if_large(value)?;
+        let stored_length = value.len();
+        let value = self.encrypt_if_enabled(value)?;
+        let value = self.chunk_if_large(value, timestamp)?;
+        self.put_lww_at(key, value, timestamp)?;
+        Ok(WriteReport { timestamp, length, stored_length })
+    }
+
+    /// Add `key` to each of the SET lattice `index_keys`
+    pub fn add_to_indexes(&mut self, key: &str, index_keys: Vec<Key>) -> Result<()> {
+        for index_key in index_keys {
+            self.add_to_set(&index_key, vec!(key.as_bytes().to_vec()))
+                .chain_err(|| format!("Could not add key '{}' to index key '{}'", key, index_key))?;
+        }
+        Ok(())
+    }
+
+    /// Write `value` to the LWW lattice of `key` with the given `timestamp`
+    pub fn put_lww_at(&mut self, key: &str, value: Vec<u8>, timestamp: u64) -> Result<()> {
+        self.put_lattice(key, LatticeType::Lww, encode(&LwwValue { timestamp, value }))
+    }
+
+    /// Read the LWW lattice of `key`. A key that has been deleted, or whose value has expired,
+    /// does not exist. Chunked values are reassembled, encrypted values decrypted and compressed
+    /// values decompressed, and the expiry header, transaction record and plain value header are
+    /// removed from the value returned.
+    pub fn get_lww(&mut self, key: &str) -> Result<LwwValue> {
+        self.get_lww_as_of(key, now_ms())
+    }
+
+    /*
+        Read the LWW value of `key` as it was at `time`, treating it as one that does not exist
+        if it was deleted, or had expired by then
+     */
+    pub(crate) fn get_lww_as_of(&mut self, key: &str, time: u64) -> Result<LwwValue> {
+        let mut lww = self.get_stored_lww(key)?;
+        lww.value = user_value(&lww.value, time)?
+            .ok_or_else(|| Error::from(ErrorKind::KeyDoesNotExist(key.into())))?;
+        Ok(lww)
+    }
+
+    /// Read the LWW value of `key` as `get_lww` does, with the time it expires at, in ms since
+    /// the UNIX epoch, if it was written with a TTL
+    pub fn get_lww_with_expiry(&mut self, key: &str) -> Result<(LwwValue, Option<u64>)> {
+        let mut lww = self.get_stored_lww(key)?;
+        let expires_at = split_expiry(&lww.value).0;
+        lww.value = user_value(&lww.value, now_ms())?
+            .ok_or_else(|| Error::from(ErrorKind::KeyDoesNotExist(key.into())))?;
+        Ok((lww, expires_at))
+    }
+
+    /*
+        Read the LWW value of `key` with the bytes stored for it, once decoded
+     */
+    fn get_stored_lww(&mut self, key: &str) -> Result<LwwValue> {
+        let mut lww: LwwValue = decode_tuple(&self.get_lattice(key)?, LatticeType::Lww)?;
+        lww.value = self.decode_stored(lww.value)
+            .chain_err(|| format!("Could not decode the value of key '{}'", key))?;
+        Ok(lww)
+    }
+
+    /// Reassemble the stored `bytes` of an LWW value if it was chunked, decrypt it if it was
+    /// encrypted and decompress it if it was compressed
+    pub fn decode_stored(&mut self, bytes: Vec<u8>) -> Result<Vec<u8>> {
+        let bytes = self.reassemble_if_chunked(bytes)?;
+        decompress(self.decrypt_if_encrypted(bytes)?)
+    }
+
+    /// Replace the values of `tuple` with the values decoded from them, if it is an LWW or SET
+    /// lattice
+    pub fn decode_stored_tuple(&mut self, tuple: KeyTuple) -> Result<KeyTuple> {
+        let payload = match tuple.lattice_type() {
+            LatticeType::Lww => {
+                let mut lww: LwwValue = decode_tuple(&tuple, LatticeType::Lww)?;
+                lww.value = self.decode_stored(lww.value)
+                    .chain_err(|| format!("Could not decode the value of key '{}'", tuple.key))?;
+                encode(&lww)
+            }
+            LatticeType::Set => {
+                let mut set: SetValue = decode_tuple(&tuple, LatticeType::Set)?;
+                set.values = self.decrypt_set_values(set.values)
+                    .chain_err(|| format!("Could not decode the values of key '{}'", tuple.key))?;
+                encode(&set)
+            }
+            LatticeType::OrderedSet => {
+                let mut set: SetValue = decode_tuple(&tuple, LatticeType::OrderedSet)?;
+                set.values = self.decrypt_set_values(set.values)
+                    .chain_err(|| format!("Could not decode the values of key '{}'", tuple.key))?;
+                set.values.sort();
+                encode(&set)
+            }
+            LatticeType::Priority => {
+                let mut priority: PriorityValue = decode_tuple(&tuple, LatticeType::Priority)?;
+                priority.value = self.decrypt_if_encrypted(priority.value)
+                    .chain_err(|| format!("Could not decode the value of key '{}'", tuple.key))?;
+                encode(&priority)
+            }
+            LatticeType::MultiCausal => {
+                let mut causal: MultiKeyCausalValue = decode_tuple(&tuple, LatticeType::MultiCausal)?;
+                causal.values = self.decrypt_set_values(causal.values)
+                    .chain_err(|| format!("Could not decode the values of key '{}'", tuple.key))?;
+                encode(&causal)
+            }
+            _ => return Ok(tuple),
+        };
+        Ok(KeyTuple { payload, ..tuple })
     }
 
-    pub fn get(&self, tokens: &[&str]) {
+    /// Add `values` to the SET lattice of `key`
+    pub fn add_to_set(&mut self, key: &str, values: Vec<Vec<u8>>) -> Result<()> {
+        let values = self.encrypt_set_values(values)?;
+        self.put_lattice(key, LatticeType::Set, encode(&SetValue { values }))
+    }
+
+    /// Read the members of the SET lattice of `key`
+    pub fn get_set_values(&mut self, key: &str) -> Result<Vec<Vec<u8>>> {
+        let set: SetValue = decode_tuple(&self.get_lattice(key)?, LatticeType::Set)?;
+        self.decrypt_set_values(set.values)
+    }
+
+    /// Add `values` to the ORDERED_SET lattice of `key`
+    pub fn add_to_ordered_set(&mut self, key: &str, values: Vec<Vec<u8>>) -> Result<()> {
+        let mut values = self.encrypt_set_values(values)?;
+        // order is not required for correctness, but helps the KVS merge efficiently
+        values.sort();
+        values.dedup();
+        self.put_lattice(key, LatticeType::OrderedSet, encode(&SetValue { values }))
+    }
+
+    /// Read the members of the ORDERED_SET lattice of `key`, in order
+    pub fn get_ordered_set_values(&mut self, key: &str) -> Result<Vec<Vec<u8>>> {
+        let set: SetValue = decode_tuple(&self.get_lattice(key)?, LatticeType::OrderedSet)?;
+        let mut values = self.decrypt_set_values(set.values)?;
+        values.sort();
+        values.dedup();
+        Ok(values)
+    }
+
+    /// Write `value` with `priority` to the PRIORITY lattice of `key`. The value with the
+    /// lowest priority written to a key is kept.
+    pub fn put_priority_value(&mut self, key: &str, priority: f64, value: Vec<u8>) -> Result<()> {
+        let value = self.encrypt_if_enabled(value)?;
+        self.put_lattice(key, LatticeType::Priority, encode(&PriorityValue { priority, value }))
+    }
+
+    /// Read the PRIORITY lattice of `key`
+    pub fn get_priority_value(&mut self, key: &str) -> Result<PriorityValue> {
+        let mut priority: PriorityValue = decode_tuple(&self.get_lattice(key)?, LatticeType::Priority)?;
+        priority.value = self.decrypt_if_encrypted(priority.value)
+            .chain_err(|| format!("Could not decode the value of key '{}'", key))?;
+        Ok(priority)
+    }
+
+    /// Store `value` in the LWW lattice of `key`, serialized using `codec`
+    pub fn put_typed<T: Serialize>(&mut self, key: &str, value: &T, codec: Codec) -> Result<u64> {
+        self.put_lww(key, codec::encode(value, codec)?)
+    }
+
+    /// Get the value in the LWW lattice of `key`, deserialized as a `T`
+    pub fn get_typed<T: DeserializeOwned>(&mut self, key: &str) -> Result<T> {
+        codec::decode(&self.get_lww(key)?.value)
+            .chain_err(|| format!("Could not decode the value of key '{}'", key))
+    }
+
+    /// Add `values` to the SET lattice of `key`, each serialized using `codec`
+    pub fn add_typed_to_set<T: Serialize>(&mut self, key: &str, values: &[T], codec: Codec) -> Result<()> {
+        let values = values.iter()
+            .map(|value| codec::encode(value, codec))
+            .collect::<Result<Vec<Vec<u8>>>>()?;
+        self.add_to_set(key, values)
+    }
+
+    /// Get the members of the SET lattice of `key`, each deserialized as a `T`
+    pub fn get_typed_set<T: DeserializeOwned>(&mut self, key: &str) -> Result<Vec<T>> {
+        self.get_set_values(key)?.iter()
+            .map(|value| codec::decode(value)
+                .chain_err(|| format!("Could not decode a member of key '{}'", key)))
+            .collect()
+    }
+
+    /*
+        GET <key> [<path> | --out <file> | --at <time>]
+
+        The value is displayed according to the type of lattice stored in the key, or the LWW
+        value of the key is written to a file. The value a versioned key had at a time, in ms
+        since the UNIX epoch or relative to now such as "-1h", is displayed with --at. Given a
+        path such as ".orders[0].status", the field at that path of a JSON value is displayed.
+     */
+    pub fn get(&mut self, tokens: &[&str]) -> Result<String> {
         debug!("GET: {:?}", tokens);
-//     vector<KeyResponse> responses = client->receive_async();
-//     while (responses.size() == 0) {
-//       responses = client->receive_async();
-//     }
-//
-//     if (responses.size() > 1) {
-//       std::cout << "Error: received more than one response" << std::endl;
-//     }
-//
-//     assert(responses[0].tuples(0).lattice_type() == LatticeType::LWW);
-//
-//     LWWPairLattice<string> lww_lattice =
-//         deserialize_lww(responses[0].tuples(0).payload());
-//     std::cout << lww_lattice.reveal().value << std::endl;
-    }
-
-    pub fn get_causal(&self, tokens: &[&str]) {
+        let key = tokens.first().ok_or("Usage: GET <key> [<path> | --out <file> | --at <time>]")?;
+        if let Some(path) = tokens.get(1).filter(|path| !path.starts_with("--")) {
+            return self.get_path(key, path);
+        }
+        if let (Some(&"--at"), Some(time)) = (tokens.get(1), tokens.get(2)) {
+            return self.get_at(key, time);
+        }
+        if let (Some(&"--out"), Some(filename)) = (tokens.get(1), tokens.get(2)) {
+            let value = self.get_lww(key)?.value;
+            std::fs::write(filename, &value)
+                .chain_err(|| format!("Could not write to file '{}'", filename))?;
+            return Ok(format!("Wrote {} bytes to '{}'", value.len(), filename));
+        }
+
+        let tuple = self.get_any(key)?;
+        if is_tombstoned(&tuple) || is_expired(&tuple) {
+            bail!(ErrorKind::KeyDoesNotExist(key.to_string()));
+        }
+        format_tuple(&tuple, self.renderer_for(key)?, false)
+    }
+
+    /*
+        INSPECT <key>
+
+        The value is displayed along with the lattice type and metadata such as timestamp,
+        vector clocks and priority
+     */
+    pub fn inspect(&mut self, tokens: &[&str]) -> Result<String> {
+        debug!("INSPECT: {:?}", tokens);
+        let key = tokens.first().ok_or("Usage: INSPECT <key>")?;
+        let tuple = self.get_any(key)?;
+        format_tuple(&tuple, self.renderer_for(key)?, true)
+    }
+
+    /*
+        Get the lattice of `key` whatever its type, adding it to the session's dependencies
+        if it is causal
+     */
+    fn get_any(&mut self, key: &str) -> Result<KeyTuple> {
+        let tuple = self.get_lattice(key)?;
+        let tuple = self.decode_stored_tuple(tuple)?;
+        match tuple.lattice_type() {
+            LatticeType::SingleCausal => {
+                let causal: SingleKeyCausalValue = decode_tuple(&tuple, LatticeType::SingleCausal)?;
+                self.session.observe(key, &causal.vector_clock);
+            }
+            LatticeType::MultiCausal => {
+                let causal: MultiKeyCausalValue = decode_tuple(&tuple, LatticeType::MultiCausal)?;
+                self.session.observe(key, &causal.vector_clock);
+            }
+            _ => {}
+        }
+        Ok(tuple)
+    }
+
+    /*
+        GET_CAUSAL <key>
+     */
+    pub fn get_causal(&mut self, tokens: &[&str]) -> Result<String> {
         debug!("GET_CAUSAL: {:?}", tokens);
+        let key = tokens.first().ok_or("Usage: GET_CAUSAL <key>")?;
+        let mut causal: MultiKeyCausalValue = decode_tuple(&self.get_lattice(key)?,
+                                                           LatticeType::MultiCausal)?;
+        causal.values = self.decrypt_set_values(causal.values)?;
+        self.session.observe(key, &causal.vector_clock);
+
+        let mut output = String::new();
+        for (id, version) in &causal.vector_clock {
+            output.push_str(&format!("{{{} : {}}}\n", id, version));
+        }
+        for dependency in &causal.dependencies {
+            output.push_str(&format!("{} : ", dependency.key));
+            for (id, version) in &dependency.vector_clock {
+                output.push_str(&format!("{{{} : {}}}\n", id, version));
+            }
+        }
+        if let Some(value) = causal.values.first() {
+            output.push_str(&String::from_utf8_lossy(value));
+        }
+        Ok(output)
     }
-//     vector<KeyResponse> responses = client->receive_async();
-//     while (responses.size() == 0) {
-//       responses = client->receive_async();
-//     }
-//
-//     if (responses.size() > 1) {
-//       std::cout << "Error: received more than one response" << std::endl;
-//     }
-//
-//     assert(responses[0].tuples(0).lattice_type() == LatticeType::MULTI_CAUSAL);
-//
-//     MultiKeyCausalLattice<SetLattice<string>> mkcl =
-//         MultiKeyCausalLattice<SetLattice<string>>(to_multi_key_causal_payload(
-//             deserialize_multi_key_causal(responses[0].tuples(0).payload())));
-//
-//     for (const auto &pair : mkcl.reveal().vector_clock.reveal()) {
-//       std::cout << "{" << pair.first << " : "
-//                 << std::to_string(pair.second.reveal()) << "}" << std::endl;
-//     }
-//
-//     for (const auto &dep_key_vc_pair : mkcl.reveal().dependencies.reveal()) {
-//       std::cout << dep_key_vc_pair.first << " : ";
-//       for (const auto &vc_pair : dep_key_vc_pair.second.reveal()) {
-//         std::cout << "{" << vc_pair.first << " : "
-//                   << std::to_string(vc_pair.second.reveal()) << "}"
-//                   << std::endl;
-//       }
-//     }
-//
-//     std::cout << *(mkcl.reveal().value.reveal().begin()) << std::endl;
-
-    pub fn put(&self, tokens: &[&str]) {
+
+    /*
+        PUT <key> <value>
+        PUT <key> --type <int | float | json | bytes> <value>
+        PUT <key> --file <file>
+
+        The value is parsed as the given type, or by the key's schema or renderer otherwise.
+        The value must be valid for the key's schema, if it has one. The contents of a file are
+        stored as is, without being parsed by the key's renderer.
+     */
+    pub fn put(&mut self, tokens: &[&str]) -> Result<String> {
         debug!("PUT: {:?}", tokens);
-        //     Key key = v[1];
-//     LWWPairLattice<string> val(
-//         TimestampValuePair<string>(generate_timestamp(0), v[2]));
-//
-//     // Put async
-//     string rid = client->put_async(key, serialize(val), LatticeType::LWW);
-//
-//     // Receive
-//     vector<KeyResponse> responses = client->receive_async();
-//     while (responses.size() == 0) {
-//       responses = client->receive_async();
-//     }
-//
-//     KeyResponse response = responses[0];
-//
-//     if (response.response_id() != rid) {
-//       std::cout << "Invalid response: ID did not match request ID!"
-//                 << std::endl;
-//     }
-//     if (response.error() == AnnaError::NO_ERROR) {
-//       std::cout << "Success!" << std::endl;
-//     } else {
-//       std::cout << "Failure!" << std::endl;
-//     }
-    }
-
-    pub fn put_causal(&self, tokens: &[&str]) {
+        if tokens.len() < 2 {
+            bail!("Usage: PUT <key> [--type <type>] <value> | PUT <key> --file <file>");
+        }
+        if let (&"--file", Some(filename)) = (&tokens[1], tokens.get(2)) {
+            let value = std::fs::read(filename)
+                .chain_err(|| format!("Could not read file '{}'", filename))?;
+            self.check_schema(tokens[0], &value)?;
+            let report = self.put_lww_reporting(tokens[0], value)?;
+            self.register_key(tokens[0])?;
+            return Ok(format!("Stored {} bytes from '{}'{}", report.length, filename,
+                              format_compression(&report)));
+        }
+
+        let value = match (tokens[1], tokens.get(2)) {
+            ("--type", Some(name)) => {
+                let value = Schema::from_name(name)?.parse(&tokens[3..].join(" "))?;
+                self.check_schema(tokens[0], &value)?;
+                value
+            }
+            _ => self.renderer_for(tokens[0])?.parse(&tokens[1..].join(" "))?,
+        };
+        let report = self.put_lww_reporting(tokens[0], value)?;
+        self.register_key(tokens[0])?;
+        Ok(format!("Success!{}", format_compression(&report)))
+    }
+
+    /*
+        PUT_CAUSAL <key> <value>
+
+        The value is written with this session's vector clock, and depends on all the keys
+        the session has previously read or written
+     */
+    pub fn put_causal(&mut self, tokens: &[&str]) -> Result<String> {
         debug!("PUT_CAUSAL: {:?}", tokens);
-//     Key key = v[1];
-//
-//     MultiKeyCausalPayload<SetLattice<string>> mkcp;
-//     // construct a test client id - version pair
-//     mkcp.vector_clock.insert("test", 1);
-//
-//     // construct one test dependencies
-//     mkcp.dependencies.insert(
-//         "dep1", VectorClock(map<string, MaxLattice<unsigned>>({{"test1", 1}})));
-//
-//     // populate the value
-//     mkcp.value.insert(v[2]);
-//
-//     MultiKeyCausalLattice<SetLattice<string>> mkcl(mkcp);
-//
-//     // Put async
-//     string rid = client->put_async(key, serialize(mkcl), LatticeType::MULTI_CAUSAL);
-//
-//     // Receive
-//     vector<KeyResponse> responses = client->receive_async();
-//     while (responses.size() == 0) {
-//       responses = client->receive_async();
-//     }
-//
-//     KeyResponse response = responses[0];
-//
-//     if (response.response_id() != rid) {
-//       std::cout << "Invalid response: ID did not match request ID!"
-//                 << std::endl;
-//     }
-//     if (response.error() == AnnaError::NO_ERROR) {
-//       std::cout << "Success!" << std::endl;
-//     } else {
-//       std::cout << "Failure!" << std::endl;
-//     }
-    }
-
-    pub fn put_set(&self, tokens: &[&str]) {
+        if tokens.len() < 2 {
+            bail!("Usage: PUT_CAUSAL <key> <value>");
+        }
+        let key = tokens[0];
+        let vector_clock = self.session.tick();
+        let dependencies = self.session.dependencies().iter()
+            .filter(|(dependency, _)| dependency.as_str() != key)
+            .map(|(dependency, clock)| KeyVersion {
+                key: dependency.clone(),
+                vector_clock: clock.clone(),
+            })
+            .collect();
+        let causal = MultiKeyCausalValue {
+            vector_clock: vector_clock.clone(),
+            dependencies,
+            values: self.encrypt_set_values(vec!(tokens[1..].join(" ").into_bytes()))?,
+        };
+        self.put_lattice(key, LatticeType::MultiCausal, encode(&causal))?;
+        self.session.observe(key, &vector_clock);
+        self.register_key(key)?;
+        Ok("Success!".into())
+    }
+
+    /*
+        PUT_SET <key> <value> [<value> ...]
+     */
+    pub fn put_set(&mut self, tokens: &[&str]) -> Result<String> {
         debug!("PUT SET: {:?}", tokens);
-        //     set<string> set;
-//     for (int i = 2; i < v.size(); i++) {
-//       set.insert(v[i]);
-//     }
-//
-//     // Put async
-//     string rid = client->put_async(v[1], serialize(SetLattice<string>(set)),
-//                                    LatticeType::SET);
-//
-//     // Receive
-//     vector<KeyResponse> responses = client->receive_async();
-//     while (responses.size() == 0) {
-//       responses = client->receive_async();
-//     }
-//
-//     KeyResponse response = responses[0];
-//
-//     if (response.response_id() != rid) {
-//       std::cout << "Invalid response: ID did not match request ID!"
-//                 << std::endl;
-//     }
-//     if (response.error() == AnnaError::NO_ERROR) {
-//       std::cout << "Success!" << std::endl;
-//     } else {
-//       std::cout << "Failure!" << std::endl;
-//     }
-    }
-
-    pub fn get_set(&self, tokens: &[&str]) {
+        if tokens.len() < 2 {
+            bail!("Usage: PUT_SET <key> <value> [<value> ...]");
+        }
+        let values = tokens[1..].iter().map(|value| value.as_bytes().to_vec()).collect();
+        self.add_to_set(tokens[0], values)?;
+        self.register_key(tokens[0])?;
+        Ok("Success!".into())
+    }
+
+    /*
+        GET_SET <key>
+     */
+    pub fn get_set(&mut self, tokens: &[&str]) -> Result<String> {
         debug!("GET SET: {:?}", tokens);
-        //     // Get Async
-//     string serialized;
-//
-//     // Receive
-//     vector<KeyResponse> responses = client->receive_async();
-//     while (responses.size() == 0) {
-//       responses = client->receive_async();
-//     }
-//
-//     SetLattice<string> latt = deserialize_set(responses[0].tuples(0).payload());
-//     print_set(latt.reveal());
+        let key = tokens.first().ok_or("Usage: GET_SET <key>")?;
+        let values = self.get_set_values(key)?;
+        let renderer = self.renderer_for(key)?;
+        let values = values.iter()
+            .map(|value| renderer.render(value))
+            .collect::<Result<Vec<String>>>()?;
+        Ok(format_set(&values))
+    }
+
+    /*
+        PUT_ORDERED <key> <value> [<value> ...]
+     */
+    pub fn put_ordered(&mut self, tokens: &[&str]) -> Result<String> {
+        debug!("PUT ORDERED: {:?}", tokens);
+        if tokens.len() < 2 {
+            bail!("Usage: PUT_ORDERED <key> <value> [<value> ...]");
+        }
+        let renderer = self.renderer_for(tokens[0])?;
+        let values = tokens[1..].iter()
+            .map(|value| renderer.parse(value))
+            .collect::<Result<Vec<Vec<u8>>>>()?;
+        self.add_to_ordered_set(tokens[0], values)?;
+        self.register_key(tokens[0])?;
+        Ok("Success!".into())
+    }
+
+    /*
+        GET_ORDERED <key>
+     */
+    pub fn get_ordered(&mut self, tokens: &[&str]) -> Result<String> {
+        debug!("GET ORDERED: {:?}", tokens);
+        let key = tokens.first().ok_or("Usage: GET_ORDERED <key>")?;
+        let values = self.get_ordered_set_values(key)?;
+        let renderer = self.renderer_for(key)?;
+        let values = values.iter()
+            .map(|value| renderer.render(value))
+            .collect::<Result<Vec<String>>>()?;
+        Ok(format_ordered_set(&values))
+    }
+
+    /*
+        PUT_PRIORITY <key> <priority> <value>
+     */
+    pub fn put_priority(&mut self, tokens: &[&str]) -> Result<String> {
+        debug!("PUT PRIORITY: {:?}", tokens);
+        if tokens.len() < 3 {
+            bail!("Usage: PUT_PRIORITY <key> <priority> <value>");
+        }
+        let priority = tokens[1].parse::<f64>()
+            .chain_err(|| format!("Priority '{}' is not a number", tokens[1]))?;
+        let value = self.renderer_for(tokens[0])?.parse(&tokens[2..].join(" "))?;
+        self.put_priority_value(tokens[0], priority, value)?;
+        self.register_key(tokens[0])?;
+        Ok("Success!".into())
+    }
+
+    /*
+        GET_PRIORITY <key>
+     */
+    pub fn get_priority(&mut self, tokens: &[&str]) -> Result<String> {
+        debug!("GET PRIORITY: {:?}", tokens);
+        let key = tokens.first().ok_or("Usage: GET_PRIORITY <key>")?;
+        let priority = self.get_priority_value(key)?;
+        Ok(format!("{} : {}", priority.priority, self.renderer_for(key)?.render(&priority.value)?))
+    }
+
+    /*
+        Issue a request for `tuple`, re-issuing it a bounded number of times if the server tells
+        us our cached worker address for the key is out of date. Its key is prefixed with the
+        namespace on the way out, and the key address cache holds the namespaced key, but the
+        tuple returned and any errors use the user's key.
+     */
+    fn try_request(&mut self, request_type: RequestType, mut tuple: KeyTuple) -> Result<KeyTuple> {
+        let user_key = tuple.key.clone();
+        let key = namespaced(self.namespace.as_deref(), &user_key)?;
+        tuple.key = key.clone();
+
+        for _ in 0..MAX_REQUEST_ATTEMPTS {
+            let worker = self.get_worker_thread(&key)
+                .chain_err(|| format!("Could not find a worker thread for key '{}'", user_key))?;
+
+            let mut request = KeyRequest {
+                r#type: request_type as i32,
+                tuples: vec!(tuple.clone()),
+                response_address: self.ut.response_connect_address(),
+                request_id: self.get_request_id(),
+            };
+            request.tuples[0].address_cache_size = self.key_address_cache.get(&key)
+                .map_or(0, |addresses| addresses.len() as u32);
+
+            self.send_request(&worker, &request)?;
+            let response: KeyResponse = match receive_response(&self.response_puller,
+                                                               self.timeout,
+                                                               &request.request_id,
+                                                               |r: &KeyResponse| &r.response_id) {
+                Ok(response) => response,
+                Err(e) => {
+                    // the worker may have failed, so don't rely on it for any key
+                    self.invalidate_cache_for_worker(&worker);
+                    return Err(e);
+                }
+            };
+
+            let mut tuple = response.tuples.into_iter().next()
+                .ok_or_else(|| format!("Response for key '{}' contained no tuples", user_key))?;
+
+            if tuple.error() == AnnaError::WrongThread {
+                info!("Server ordered invalidation of key address cache for key {}. Retrying request.", user_key);
+                self.invalidate_cache_for_key(&key, &tuple);
+                continue;
+            }
+
+            if tuple.invalidate {
+                self.invalidate_cache_for_key(&key, &tuple);
+                info!("Server ordered invalidation of key address cache for key {}", user_key);
+            }
+
+            tuple.key = user_key;
+            return Ok(tuple);
+        }
+
+        bail!("Request for key '{}' failed after {} attempts", user_key, MAX_REQUEST_ATTEMPTS)
+    }
+
+    /*
+        Returns one (randomly chosen) worker thread for the key. If there are no cached
+        threads, a query is sent to the routing tier.
+     */
+    fn get_worker_thread(&mut self, key: &Key) -> Result<Address> {
+        if self.key_address_cache.get(key).is_none_or(|addresses| addresses.is_empty()) {
+            let addresses = self.query_routing(key)?;
+            self.key_address_cache.insert(key.clone(), addresses);
+        }
+
+        let workers = &self.key_address_cache[key];
+        if workers.is_empty() {
+            bail!("No worker threads found");
+        }
+        let index = self.rng.gen_range(0..workers.len());
+        workers.iter().nth(index).cloned()
+            .ok_or_else(|| "No worker threads found".into())
+    }
+
+    /*
+        Send a synchronous query to the routing tier for the worker addresses of `key`
+     */
+    fn query_routing(&mut self, key: &Key) -> Result<HashSet<Address>> {
+        let request = KeyAddressRequest {
+            response_address: self.ut.key_address_connect_address(),
+            keys: vec!(key.clone()),
+            request_id: self.get_request_id(),
+        };
+
+        let routing_thread = self.get_routing_thread();
+        self.send_request(&routing_thread, &request)?;
+        let response: KeyAddressResponse = receive_response(&self.key_address_puller,
+                                                            self.timeout,
+                                                            &request.request_id,
+                                                            |r: &KeyAddressResponse| &r.response_id)?;
+
+        if response.error() == AnnaError::NoServers {
+            bail!(ErrorKind::ClusterUnreachable("No servers have joined the cluster yet".into()));
+        }
+
+        Ok(response.addresses.into_iter()
+            .filter(|address| &address.key == key)
+            .flat_map(|address| address.ips)
+            .collect())
+    }
+
+    /*
+        Serialize and send `request` to `address`, using a cached socket if there is one
+     */
+    fn send_request<M: Message>(&mut self, address: &Address, request: &M) -> Result<()> {
+        if !self.socket_cache.contains_key(address) {
+            let socket = self.context.socket(zmq::PUSH)
+                .chain_err(|| "Could not create request socket")?;
+            socket.connect(address)
+                .chain_err(|| format!("Could not connect to '{}'", address))?;
+            self.socket_cache.insert(address.clone(), socket);
+        }
+
+        self.socket_cache[address].send(encode(request), 0)
+            .chain_err(|| format!("Could not send request to '{}'", address))
     }
 
     /*
@@ -388,375 +1168,203 @@ impl KVSClient {
     fn invalidate_cache_for_key(&mut self, key: &Key, _tuple: &KeyTuple) {
         self.key_address_cache.remove(key);
     }
+
+    /*
+     * Invalidate the key caches for any key that previously had this worker in
+     * its cache. The underlying assumption is that if the worker timed out, it
+     * might have failed, and so we don't want to rely on it being alive for both
+     * the key we were querying and any other key.
+     */
+    fn invalidate_cache_for_worker(&mut self, worker: &Address) {
+        let signature = worker.split(':').nth(1).unwrap_or_default().to_string();
+        self.key_address_cache.retain(|_, addresses|
+            !addresses.iter().any(|address| address.split(':').nth(1) == Some(&signature)));
+    }
 }
 
-// //
-// //   /**
-// //    * Issue an async PUT request to the KVS for a certain lattice typed value.
-// //    */
-// //   string put_async(const Key& key, const string& payload,
-// //                    LatticeType lattice_type) {
-// //     KeyRequest request;
-// //     KeyTuple* tuple = prepare_data_request(request, key);
-// //     request.set_type(RequestType::PUT);
-// //     tuple->set_lattice_type(lattice_type);
-// //     tuple->set_payload(payload);
-// //
-// //     try_request(request);
-// //     return request.request_id();
-// //   }
-// //
-// //   /**
-// //    * Issue an async GET request to the KVS.
-// //    */
-// //   void get_async(const Key& key) {
-// //     // we issue GET only when it is not in the pending map
-// //     if (pending_get_response_map_.find(key) ==
-// //         pending_get_response_map_.end()) {
-// //       KeyRequest request;
-// //       prepare_data_request(request, key);
-// //       request.set_type(RequestType::GET);
-// //
-// //       try_request(request);
-// //     }
-// //   }
-// //
-// //   vector<KeyResponse> receive_async() {
-// //     vector<KeyResponse> result;
-// //     kZmqUtil->poll(0, &pollitems_);
-// //
-// //     if (pollitems_[0].revents & ZMQ_POLLIN) {
-// //       string serialized = kZmqUtil->recv_string(&key_address_puller_);
-// //       KeyAddressResponse response;
-// //       response.ParseFromString(serialized);
-// //       Key key = response.addresses(0).key();
-// //
-// //       if (pending_request_map_.find(key) != pending_request_map_.end()) {
-// //         if (response.error() == AnnaError::NO_SERVERS) {
-// //           log_->error(
-// //               "No servers have joined the cluster yet. Retrying request.");
-// //           pending_request_map_[key].first = std::chrono::system_clock::now();
-// //
-// //           query_routing_async(key);
-// //         } else {
-// //           // populate cache
-// //           for (const Address& ip : response.addresses(0).ips()) {
-// //             key_address_cache_[key].insert(ip);
-// //           }
-// //
-// //           // handle stuff in pending request map
-// //           for (auto& req : pending_request_map_[key].second) {
-// //             try_request(req);
-// //           }
-// //
-// //           // GC the pending request map
-// //           pending_request_map_.erase(key);
-// //         }
-// //       }
-// //     }
-// //
-// //     if (pollitems_[1].revents & ZMQ_POLLIN) {
-// //       string serialized = kZmqUtil->recv_string(&response_puller_);
-// //       KeyResponse response;
-// //       response.ParseFromString(serialized);
-// //       Key key = response.tuples(0).key();
-// //
-// //       if (response.type() == RequestType::GET) {
-// //         if (pending_get_response_map_.find(key) !=
-// //             pending_get_response_map_.end()) {
-// //           if (check_tuple(response.tuples(0))) {
-// //             // error no == 2, so re-issue request
-// //             pending_get_response_map_[key].tp_ =
-// //                 std::chrono::system_clock::now();
-// //
-// //             try_request(pending_get_response_map_[key].request_);
-// //           } else {
-// //             // error no == 0 or 1
-// //             result.push_back(response);
-// //             pending_get_response_map_.erase(key);
-// //           }
-// //         }
-// //       } else {
-// //         if (pending_put_response_map_.find(key) !=
-// //                 pending_put_response_map_.end() &&
-// //             pending_put_response_map_[key].find(response.response_id()) !=
-// //                 pending_put_response_map_[key].end()) {
-// //           if (check_tuple(response.tuples(0))) {
-// //             // error no == 2, so re-issue request
-// //             pending_put_response_map_[key][response.response_id()].tp_ =
-// //                 std::chrono::system_clock::now();
-// //
-// //             try_request(pending_put_response_map_[key][response.response_id()]
-// //                             .request_);
-// //           } else {
-// //             // error no == 0
-// //             result.push_back(response);
-// //             pending_put_response_map_[key].erase(response.response_id());
-// //
-// //             if (pending_put_response_map_[key].size() == 0) {
-// //               pending_put_response_map_.erase(key);
-// //             }
-// //           }
-// //         }
-// //       }
-// //     }
-// //
-// //     // GC the pending request map
-// //     set<Key> to_remove;
-// //     for (const auto& pair : pending_request_map_) {
-// //       if (std::chrono::duration_cast<std::chrono::milliseconds>(
-// //               std::chrono::system_clock::now() - pair.second.first)
-// //               .count() > timeout_) {
-// //         // query to the routing tier timed out
-// //         for (const auto& req : pair.second.second) {
-// //           result.push_back(generate_bad_response(req));
-// //         }
-// //
-// //         to_remove.insert(pair.first);
-// //       }
-// //     }
-// //
-// //     for (const Key& key : to_remove) {
-// //       pending_request_map_.erase(key);
-// //     }
-// //
-// //     // GC the pending get response map
-// //     to_remove.clear();
-// //     for (const auto& pair : pending_get_response_map_) {
-// //       if (std::chrono::duration_cast<std::chrono::milliseconds>(
-// //               std::chrono::system_clock::now() - pair.second.tp_)
-// //               .count() > timeout_) {
-// //         // query to server timed out
-// //         result.push_back(generate_bad_response(pair.second.request_));
-// //         to_remove.insert(pair.first);
-// //         invalidate_cache_for_worker(pair.second.worker_addr_);
-// //       }
-// //     }
-// //
-// //     for (const Key& key : to_remove) {
-// //       pending_get_response_map_.erase(key);
-// //     }
-// //
-// //     // GC the pending put response map
-// //     map<Key, set<string>> to_remove_put;
-// //     for (const auto& key_map_pair : pending_put_response_map_) {
-// //       for (const auto& id_map_pair :
-// //            pending_put_response_map_[key_map_pair.first]) {
-// //         if (std::chrono::duration_cast<std::chrono::milliseconds>(
-// //                 std::chrono::system_clock::now() -
-// //                 pending_put_response_map_[key_map_pair.first][id_map_pair.first]
-// //                     .tp_)
-// //                 .count() > timeout_) {
-// //           result.push_back(generate_bad_response(id_map_pair.second.request_));
-// //           to_remove_put[key_map_pair.first].insert(id_map_pair.first);
-// //           invalidate_cache_for_worker(id_map_pair.second.worker_addr_);
-// //         }
-// //       }
-// //     }
-// //
-// //     for (const auto& key_set_pair : to_remove_put) {
-// //       for (const auto& id : key_set_pair.second) {
-// //         pending_put_response_map_[key_set_pair.first].erase(id);
-// //       }
-// //     }
-// //
-// //     return result;
-// //   }
-// //
-
-
-//
-// //   /**
-// //    * A recursive helper method for the get and put implementations that tries
-// //    * to issue a request at most trial_limit times before giving up. It  checks
-// //    * for the default failure modes (timeout, errno == 2, and cache
-// //    * invalidation). If there are no issues, it returns the set of responses to
-// //    * the respective implementations for them to deal with. This is the same as
-// //    * the above implementation of try_multi_request, except it only operates on
-// //    * a single request.
-// //    */
-// //   void try_request(KeyRequest& request) {
-// //     // we only get NULL back for the worker thread if the query to the routing
-// //     // tier timed out, which should never happen.
-// //     Key key = request.tuples(0).key();
-// //     Address worker = get_worker_thread(key);
-// //     if (worker.length() == 0) {
-// //       // this means a key addr request is issued asynchronously
-// //       if (pending_request_map_.find(key) == pending_request_map_.end()) {
-// //         pending_request_map_[key].first = std::chrono::system_clock::now();
-// //       }
-// //       pending_request_map_[key].second.push_back(request);
-// //       return;
-// //     }
-// //
-// //     request.mutable_tuples(0)->set_address_cache_size(
-// //         key_address_cache_[key].size());
-// //
-// //     send_request<KeyRequest>(request, socket_cache_[worker]);
-// //
-// //     if (request.type() == RequestType::GET) {
-// //       if (pending_get_response_map_.find(key) ==
-// //           pending_get_response_map_.end()) {
-// //         pending_get_response_map_[key].tp_ = std::chrono::system_clock::now();
-// //         pending_get_response_map_[key].request_ = request;
-// //       }
-// //
-// //       pending_get_response_map_[key].worker_addr_ = worker;
-// //     } else {
-// //       if (pending_put_response_map_[key].find(request.request_id()) ==
-// //           pending_put_response_map_[key].end()) {
-// //         pending_put_response_map_[key][request.request_id()].tp_ =
-// //             std::chrono::system_clock::now();
-// //         pending_put_response_map_[key][request.request_id()].request_ = request;
-// //       }
-// //       pending_put_response_map_[key][request.request_id()].worker_addr_ =
-// //           worker;
-// //     }
-// //   }
-// //
-// //   /**
-// //    * A helper method to check for the default failure modes for a request that
-// //    * retrieves a response. It returns true if the caller method should reissue
-// //    * the request (this happens if errno == 2). Otherwise, it returns false. It
-// //    * invalidates the local cache if the information is out of date.
-// //    */
-// //   bool check_tuple(const KeyTuple& tuple) {
-// //     Key key = tuple.key();
-// //     if (tuple.error() == 2) {
-// //       log_->info(
-// //           "Server ordered invalidation of key address cache for key {}. "
-// //           "Retrying request.",
-// //           key);
-// //
-// //       invalidate_cache_for_key(key, tuple);
-// //       return true;
-// //     }
-// //
-// //     if (tuple.invalidate()) {
-// //       invalidate_cache_for_key(key, tuple);
-// //
-// //       log_->info("Server ordered invalidation of key address cache for key {}",
-// //                  key);
-// //     }
-// //
-// //     return false;
-// //   }
-// //
-
-//
-// //
-// //   /**
-// //    * Invalidate the key caches for any key that previously had this worker in
-// //    * its cache. The underlying assumption is that if the worker timed out, it
-// //    * might have failed, and so we don't want to rely on it being alive for both
-// //    * the key we were querying and any other key.
-// //    */
-// //   void invalidate_cache_for_worker(const Address& worker) {
-// //     vector<string> tokens;
-// //     split(worker, ':', tokens);
-// //     string signature = tokens[1];
-// //     set<Key> remove_set;
-// //
-// //     for (const auto& key_pair : key_address_cache_) {
-// //       for (const string& address : key_pair.second) {
-// //         vector<string> v;
-// //         split(address, ':', v);
-// //
-// //         if (v[1] == signature) {
-// //           remove_set.insert(key_pair.first);
-// //         }
-// //       }
-// //     }
-// //
-// //     for (const string& key : remove_set) {
-// //       key_address_cache_.erase(key);
-// //     }
-// //   }
-// //
-// //   /**
-// //    * Prepare a data request object by populating the request ID, the key for
-// //    * the request, and the response address. This method modifies the passed-in
-// //    * KeyRequest and also returns a pointer to the KeyTuple contained by this
-// //    * request.
-// //    */
-// //   KeyTuple* prepare_data_request(KeyRequest& request, const Key& key) {
-// //     request.set_request_id(get_request_id());
-// //     request.set_response_address(ut_.response_connect_address());
-// //
-// //     KeyTuple* tp = request.add_tuples();
-// //     tp->set_key(key);
-// //
-// //     return tp;
-// //   }
-// //
-// //   /**
-// //    * returns all the worker threads for the key queried. If there are no cached
-// //    * threads, a request is sent to the routing tier. If the query times out,
-// //    * NULL is returned.
-// //    */
-// //   set<Address> get_all_worker_threads(const Key& key) {
-// //     if (key_address_cache_.find(key) == key_address_cache_.end() ||
-// //         key_address_cache_[key].size() == 0) {
-// //       if (pending_request_map_.find(key) == pending_request_map_.end()) {
-// //         query_routing_async(key);
-// //       }
-// //       return set<Address>();
-// //     } else {
-// //       return key_address_cache_[key];
-// //     }
-// //   }
-// //
-// //   /**
-// //    * Similar to the previous method, but only returns one (randomly chosen)
-// //    * worker address instead of all of them.
-// //    */
-// //   Address get_worker_thread(const Key& key) {
-// //     set<Address> local_cache = get_all_worker_threads(key);
-// //
-// //     // This will be empty if the worker threads are not cached locally
-// //     if (local_cache.size() == 0) {
-// //       return "";
-// //     }
-// //
-// //     return *(next(begin(local_cache), rand_r(&seed_) % local_cache.size()));
-// //   }
-// //
-// //
-// //   /**
-// //    * Send a query to the routing tier asynchronously.
-// //    */
-// //   void query_routing_async(const Key& key) {
-// //     // define protobuf request objects
-// //     KeyAddressRequest request;
-// //
-// //     // populate request with response address, request id, etc.
-// //     request.set_request_id(get_request_id());
-// //     request.set_response_address(ut_.key_address_connect_address());
-// //     request.add_keys(key);
-// //
-// //     Address rt_thread = get_routing_thread();
-// //     send_request<KeyAddressRequest>(request, socket_cache_[rt_thread]);
-// //   }
-// //
-//
-// //
-// //   KeyResponse generate_bad_response(const KeyRequest& req) {
-// //     KeyResponse resp;
-// //
-// //     resp.set_type(req.type());
-// //     resp.set_response_id(req.request_id());
-// //     resp.set_error(AnnaError::TIMEOUT);
-// //
-// //     KeyTuple* tp = resp.add_tuples();
-// //     tp->set_key(req.tuples(0).key());
-// //
-// //     if (req.type() == RequestType::PUT) {
-// //       tp->set_lattice_type(req.tuples(0).lattice_type());
-// //       tp->set_payload(req.tuples(0).payload());
-// //     }
-// //
-// //     return resp;
-// //   }
-// //
-// // };
-// }
\ No newline at end of file
+/*
+    Wait for the response with id `response_id` to arrive on `socket`, discarding any
+    responses to earlier requests, for at most `timeout` ms
+ */
+fn receive_response<M, F>(socket: &Socket, timeout: usize, response_id: &str, id_of: F) -> Result<M>
+    where M: Message + Default, F: Fn(&M) -> &String {
+    let deadline = Instant::now() + Duration::from_millis(timeout as u64);
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let ready = socket.poll(zmq::POLLIN, remaining.as_millis() as i64)
+            .chain_err(|| "Could not poll for a response")?;
+        if ready == 0 {
+            bail!(ErrorKind::ClusterUnreachable(
+                format!("Timed out waiting for response to request '{}'", response_id)));
+        }
+
+        let bytes = socket.recv_bytes(0).chain_err(|| "Could not receive response")?;
+        let response = M::decode(bytes.as_slice()).chain_err(|| "Could not decode response")?;
+        if id_of(&response) == response_id {
+            return Ok(response);
+        }
+        debug!("Discarding response to an earlier request");
+    }
+}
+
+/*
+    Describe the compression of a value written, for the output of the CLI
+ */
+fn format_compression(report: &WriteReport) -> String {
+    if report.compressed() {
+        format!(" (compressed {} to {} bytes, {:.1}x)", report.length, report.stored_length,
+                report.compression_ratio())
+    } else {
+        String::new()
+    }
+}
+
+/// Return an error if `namespace` cannot be used. Namespaces cannot contain '/', which separates
+/// them from keys, so that keys in different namespaces cannot collide.
+pub fn check_namespace(namespace: &str) -> Result<()> {
+    if namespace.is_empty() {
+        bail!("The namespace cannot be empty");
+    }
+    if namespace.contains('/') {
+        bail!("The namespace '{}' cannot contain '/'", namespace);
+    }
+    check_not_reserved(namespace)?;
+    check_user_key(namespace)
+}
+
+/// Return `key` prefixed with `namespace`, checking the result does not collide with the keys
+/// `anna` reserves for its own metadata
+pub fn namespaced(namespace: Option<&str>, key: &str) -> Result<Key> {
+    let namespaced = match namespace {
+        Some(namespace) => format!("{}/{}", namespace, key),
+        None => key.to_string(),
+    };
+    check_not_reserved(&namespaced).chain_err(|| format!("Key '{}' cannot be used", key))?;
+    Ok(namespaced)
+}
+
+/// Return an error if `key` is, or starts with, the identifier of `anna`'s metadata keys
+pub fn check_not_reserved(key: &str) -> Result<()> {
+    if key.split(METADATA_DELIMITER).next() == Some(METADATA_IDENTIFIER) {
+        bail!("Keys starting with '{}{}' are reserved for anna's metadata", METADATA_IDENTIFIER,
+              METADATA_DELIMITER);
+    }
+    Ok(())
+}
+
+/// Return an error if `key` starts with the prefix of the keys the client stores its own
+/// metadata under, which users must not read or write directly
+pub fn check_user_key(key: &str) -> Result<()> {
+    if key.starts_with(CLIENT_METADATA_PREFIX) {
+        bail!("Keys starting with '{}' are reserved for the client's metadata", CLIENT_METADATA_PREFIX);
+    }
+    Ok(())
+}
+
+/// Decode the lattice payload of `tuple`, checking it is of the `expected` lattice type
+pub fn decode_tuple<M: Message + Default>(tuple: &KeyTuple, expected: LatticeType) -> Result<M> {
+    if tuple.lattice_type() != expected {
+        bail!("Key '{}' holds a {:?} lattice, not {:?}", tuple.key, tuple.lattice_type(), expected);
+    }
+    M::decode(tuple.payload.as_slice())
+        .chain_err(|| format!("Could not decode {:?} lattice of key '{}'", expected, tuple.key))
+}
+
+/*
+    Return the value a user wrote from the decoded stored `bytes` of an LWW value, or None if
+    they are a tombstone or had expired at `time`
+ */
+fn user_value(bytes: &[u8], time: u64) -> Result<Option<Vec<u8>>> {
+    let (expires_at, bytes) = split_expiry(bytes);
+    if is_tombstone(bytes) || expires_at.is_some_and(|expires_at| expires_at <= time) {
+        return Ok(None);
+    }
+    let value = match decode_record(bytes)? {
+        Some(record) => record.value,
+        None => bytes.to_vec(),
+    };
+    Ok(Some(strip_plain(&value).to_vec()))
+}
+
+/// Convert the result of reading a key that does not exist into the default value, so that
+/// a missing key can be treated as an empty lattice
+pub fn default_if_missing<T: Default>(result: Result<T>) -> Result<T> {
+    match result {
+        Err(Error(ErrorKind::KeyDoesNotExist(_), _)) => Ok(T::default()),
+        result => result,
+    }
+}
+
+/// Serialize a protobuf `message` into a new byte vector
+pub fn encode<M: Message>(message: &M) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(message.encoded_len());
+    // Encoding can only fail due to lack of capacity, and the Vec grows as required
+    let _ = message.encode(&mut buf);
+    buf
+}
+
+/// Generate a timestamp for an LWW write from the current time in ms, with the id of the
+/// writer appended as the least significant digits to make it unique between writers
+pub fn generate_timestamp(id: usize) -> u64 {
+    let time = SystemTime::now().duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::from_micros(42)).as_millis() as u64;
+    let mut pow = 10;
+    while id as u64 >= pow {
+        pow *= 10;
+    }
+    time * pow + id as u64
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_namespace, check_user_key, generate_timestamp, namespaced, user_value};
+    use crate::envelope::tag_plain;
+    use crate::expiry::with_expiry;
+
+    #[test]
+    fn timestamp_includes_id() {
+        assert_eq!(generate_timestamp(7) % 10, 7);
+        assert_eq!(generate_timestamp(42) % 100, 42);
+    }
+
+    #[test]
+    fn values_are_read_as_of_a_time() {
+        let value = with_expiry(&tag_plain(b"session"), 100);
+        assert_eq!(user_value(&value, 99).expect("value"), Some(b"session".to_vec()));
+        assert_eq!(user_value(&value, 100).expect("value"), None);
+        assert_eq!(user_value(b"\xA7D", 0).expect("value"), None);
+        assert_eq!(user_value(&tag_plain(b"\xA7D"), 0).expect("value"), Some(b"\xA7D".to_vec()));
+    }
+
+    #[test]
+    fn keys_are_namespaced() {
+        assert_eq!(namespaced(Some("team-a"), "orders/1").expect("namespaced"), "team-a/orders/1");
+        assert_eq!(namespaced(None, "orders/1").expect("namespaced"), "orders/1");
+    }
+
+    #[test]
+    fn namespaces_cannot_contain_slashes() {
+        assert!(check_namespace("team-a").is_ok());
+        assert!(check_namespace("team/a").is_err());
+        assert!(check_namespace("").is_err());
+        assert!(check_namespace("ANNA_METADATA").is_err());
+    }
+
+    #[test]
+    fn metadata_keys_are_reserved() {
+        assert!(namespaced(None, "ANNA_METADATA|replication|key").is_err());
+        assert!(namespaced(None, "ANNA_METADATA").is_err());
+        assert!(namespaced(None, "ANNA_METADATA_BACKUP").is_ok());
+        assert!(namespaced(Some("ANNA_METADATA|"), "key").is_err());
+        assert!(namespaced(Some("team-a"), "ANNA_METADATA|key").is_ok());
+    }
+
+    #[test]
+    fn client_metadata_keys_are_reserved() {
+        assert!(check_user_key("ANNA_CLIENT|tombstones").is_err());
+        assert!(check_user_key("ANNA_CLIENT").is_ok());
+        assert!(check_user_key("orders/ANNA_CLIENT|1").is_ok());
+        assert!(check_namespace("ANNA_CLIENT|team-a").is_err());
+    }
+}
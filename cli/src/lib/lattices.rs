@@ -0,0 +1,439 @@
+//! Lattices module contains the Rust versions of the lattices `anna` uses to merge
+//! conflicting updates without coordination.
+//!
+//! Each lattice's merge must be associative, commutative and idempotent, which can be verified
+//! for any `Lattice` implementation using the helpers in the `laws` module.
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::hash::Hash;
+
+use crate::kvs_client::Key;
+
+pub mod laws;
+
+/// `Lattice` is implemented by all lattice types, holding an `Element` that is merged with
+/// others to resolve conflicting updates
+pub trait Lattice {
+    /// The type of element held in the lattice
+    type Element;
+
+    /// Return a reference to the element held in the lattice
+    fn reveal(&self) -> &Self::Element;
+
+    /// Replace the element held in the lattice with `element`
+    fn assign(&mut self, element: Self::Element);
+
+    /// Merge `element` into the element held in the lattice
+    fn merge_element(&mut self, element: &Self::Element);
+
+    /// Merge another lattice of the same type into this one
+    fn merge(&mut self, other: &Self) {
+        self.merge_element(other.reveal())
+    }
+}
+
+/// A lattice over `bool` that merges using logical OR
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BoolLattice(bool);
+
+impl BoolLattice {
+    pub fn new(element: bool) -> Self {
+        BoolLattice(element)
+    }
+}
+
+impl Lattice for BoolLattice {
+    type Element = bool;
+
+    fn reveal(&self) -> &bool {
+        &self.0
+    }
+
+    fn assign(&mut self, element: bool) {
+        self.0 = element;
+    }
+
+    fn merge_element(&mut self, element: &bool) {
+        self.0 |= element;
+    }
+}
+
+/// A lattice that merges by keeping the maximum of the two elements
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MaxLattice<T>(T);
+
+impl<T> MaxLattice<T> {
+    pub fn new(element: T) -> Self {
+        MaxLattice(element)
+    }
+}
+
+impl<T: PartialOrd + Clone> Lattice for MaxLattice<T> {
+    type Element = T;
+
+    fn reveal(&self) -> &T {
+        &self.0
+    }
+
+    fn assign(&mut self, element: T) {
+        self.0 = element;
+    }
+
+    fn merge_element(&mut self, element: &T) {
+        if self.0 < *element {
+            self.0 = element.clone();
+        }
+    }
+}
+
+/// A grow-only set lattice, that merges using set union
+#[derive(Clone, Debug, PartialEq)]
+pub struct SetLattice<T: Eq + Hash>(HashSet<T>);
+
+impl<T: Eq + Hash> SetLattice<T> {
+    pub fn new(element: HashSet<T>) -> Self {
+        SetLattice(element)
+    }
+
+    pub fn size(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn insert(&mut self, value: T) {
+        self.0.insert(value);
+    }
+}
+
+impl<T: Eq + Hash> Default for SetLattice<T> {
+    fn default() -> Self {
+        SetLattice(HashSet::new())
+    }
+}
+
+impl<T: Eq + Hash + Clone> Lattice for SetLattice<T> {
+    type Element = HashSet<T>;
+
+    fn reveal(&self) -> &HashSet<T> {
+        &self.0
+    }
+
+    fn assign(&mut self, element: HashSet<T>) {
+        self.0 = element;
+    }
+
+    fn merge_element(&mut self, element: &HashSet<T>) {
+        self.0.extend(element.iter().cloned());
+    }
+}
+
+/// A grow-only set lattice whose members are kept in order, that merges using set union
+#[derive(Clone, Debug, PartialEq)]
+pub struct OrderedSetLattice<T: Ord>(BTreeSet<T>);
+
+impl<T: Ord> OrderedSetLattice<T> {
+    pub fn new(element: BTreeSet<T>) -> Self {
+        OrderedSetLattice(element)
+    }
+
+    pub fn size(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn insert(&mut self, value: T) {
+        self.0.insert(value);
+    }
+}
+
+impl<T: Ord> Default for OrderedSetLattice<T> {
+    fn default() -> Self {
+        OrderedSetLattice(BTreeSet::new())
+    }
+}
+
+impl<T: Ord + Clone> Lattice for OrderedSetLattice<T> {
+    type Element = BTreeSet<T>;
+
+    fn reveal(&self) -> &BTreeSet<T> {
+        &self.0
+    }
+
+    fn assign(&mut self, element: BTreeSet<T>) {
+        self.0 = element;
+    }
+
+    fn merge_element(&mut self, element: &BTreeSet<T>) {
+        self.0.extend(element.iter().cloned());
+    }
+}
+
+/// A map lattice whose values are themselves lattices. Merging merges the values of keys
+/// present in both maps, and adds keys only present in one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MapLattice<K: Eq + Hash, V>(HashMap<K, V>);
+
+impl<K: Eq + Hash, V> MapLattice<K, V> {
+    pub fn new(element: HashMap<K, V>) -> Self {
+        MapLattice(element)
+    }
+
+    pub fn size(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn contains(&self, key: &K) -> bool {
+        self.0.contains_key(key)
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.0.get(key)
+    }
+}
+
+impl<K: Eq + Hash, V: Lattice + Clone> MapLattice<K, V> {
+    pub fn insert(&mut self, key: K, value: &V) {
+        match self.0.get_mut(&key) {
+            Some(current) => current.merge(value),
+            None => {
+                self.0.insert(key, value.clone());
+            }
+        }
+    }
+}
+
+impl<K: Eq + Hash, V> Default for MapLattice<K, V> {
+    fn default() -> Self {
+        MapLattice(HashMap::new())
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Lattice + Clone> Lattice for MapLattice<K, V> {
+    type Element = HashMap<K, V>;
+
+    fn reveal(&self) -> &HashMap<K, V> {
+        &self.0
+    }
+
+    fn assign(&mut self, element: HashMap<K, V>) {
+        self.0 = element;
+    }
+
+    fn merge_element(&mut self, element: &HashMap<K, V>) {
+        for (key, value) in element {
+            self.insert(key.clone(), value);
+        }
+    }
+}
+
+/// A value with the timestamp it was written at
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TimestampValuePair<T> {
+    pub timestamp: u64,
+    pub value: T,
+}
+
+/// A last-writer-wins lattice, where the value with the latest timestamp is kept. Of values
+/// with the same timestamp, the greatest is kept, so that merges commute.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LWWPairLattice<T>(TimestampValuePair<T>);
+
+impl<T> LWWPairLattice<T> {
+    pub fn new(timestamp: u64, value: T) -> Self {
+        LWWPairLattice(TimestampValuePair { timestamp, value })
+    }
+}
+
+impl<T: Clone + Ord> Lattice for LWWPairLattice<T> {
+    type Element = TimestampValuePair<T>;
+
+    fn reveal(&self) -> &TimestampValuePair<T> {
+        &self.0
+    }
+
+    fn assign(&mut self, element: TimestampValuePair<T>) {
+        self.0 = element;
+    }
+
+    fn merge_element(&mut self, element: &TimestampValuePair<T>) {
+        let (timestamp, current) = (element.timestamp, self.0.timestamp);
+        if timestamp > current || (timestamp == current && element.value > self.0.value) {
+            self.0 = element.clone();
+        }
+    }
+}
+
+/// A value with the priority it was written with
+#[derive(Clone, Debug, PartialEq)]
+pub struct PriorityValuePair<V> {
+    pub priority: f64,
+    pub value: V,
+}
+
+impl<V: Default> Default for PriorityValuePair<V> {
+    // Initialize at a high value since the merge logic is taking the minimum
+    fn default() -> Self {
+        PriorityValuePair {
+            priority: f64::MAX,
+            value: V::default(),
+        }
+    }
+}
+
+/// A lattice that keeps the value with the lowest priority. Of values with the same priority,
+/// the least is kept, so that merges commute.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PriorityLattice<V>(PriorityValuePair<V>);
+
+impl<V> PriorityLattice<V> {
+    pub fn new(priority: f64, value: V) -> Self {
+        PriorityLattice(PriorityValuePair { priority, value })
+    }
+}
+
+impl<V: Clone + Ord> Lattice for PriorityLattice<V> {
+    type Element = PriorityValuePair<V>;
+
+    fn reveal(&self) -> &PriorityValuePair<V> {
+        &self.0
+    }
+
+    fn assign(&mut self, element: PriorityValuePair<V>) {
+        self.0 = element;
+    }
+
+    fn merge_element(&mut self, element: &PriorityValuePair<V>) {
+        let (priority, current) = (element.priority, self.0.priority);
+        if priority < current || (priority == current && element.value < self.0.value) {
+            self.0 = element.clone();
+        }
+    }
+}
+
+/// A `VectorClock` lattice maps a client id to the latest version seen from that client
+pub type VectorClock = MapLattice<String, MaxLattice<u32>>;
+
+/// A value with the vector clock of the version it belongs to
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VectorClockValuePair<T> {
+    pub vector_clock: VectorClock,
+    pub value: T,
+}
+
+/// A lattice holding causally consistent versions of a single key. A version whose vector
+/// clock dominates replaces the current one, while concurrent versions are merged.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SingleKeyCausalLattice<T>(VectorClockValuePair<T>);
+
+impl<T> SingleKeyCausalLattice<T> {
+    pub fn new(vector_clock: VectorClock, value: T) -> Self {
+        SingleKeyCausalLattice(VectorClockValuePair { vector_clock, value })
+    }
+}
+
+impl<T: Lattice + Clone> Lattice for SingleKeyCausalLattice<T> where T::Element: Clone {
+    type Element = VectorClockValuePair<T>;
+
+    fn reveal(&self) -> &VectorClockValuePair<T> {
+        &self.0
+    }
+
+    fn assign(&mut self, element: VectorClockValuePair<T>) {
+        self.0 = element;
+    }
+
+    fn merge_element(&mut self, element: &VectorClockValuePair<T>) {
+        let previous = self.0.vector_clock.clone();
+        self.0.vector_clock.merge(&element.vector_clock);
+
+        if self.0.vector_clock == element.vector_clock {
+            // incoming version is dominating
+            self.0.value.assign(element.value.reveal().clone());
+        } else if self.0.vector_clock != previous {
+            // versions are concurrent
+            self.0.value.merge(&element.value);
+        }
+    }
+}
+
+/// A value with the vector clock of the version it belongs to and the versions of other
+/// keys it depends on
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MultiKeyCausalPayload<T> {
+    pub vector_clock: VectorClock,
+    pub dependencies: MapLattice<Key, VectorClock>,
+    pub value: T,
+}
+
+/// A lattice holding causally consistent versions of a key that depends on other keys.
+/// A version whose vector clock dominates replaces the current one, while concurrent versions
+/// are merged along with their dependencies.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MultiKeyCausalLattice<T>(MultiKeyCausalPayload<T>);
+
+impl<T> MultiKeyCausalLattice<T> {
+    pub fn new(vector_clock: VectorClock, dependencies: MapLattice<Key, VectorClock>, value: T) -> Self {
+        MultiKeyCausalLattice(MultiKeyCausalPayload { vector_clock, dependencies, value })
+    }
+}
+
+impl<T: Lattice + Clone> Lattice for MultiKeyCausalLattice<T> where T::Element: Clone {
+    type Element = MultiKeyCausalPayload<T>;
+
+    fn reveal(&self) -> &MultiKeyCausalPayload<T> {
+        &self.0
+    }
+
+    fn assign(&mut self, element: MultiKeyCausalPayload<T>) {
+        self.0 = element;
+    }
+
+    fn merge_element(&mut self, element: &MultiKeyCausalPayload<T>) {
+        let previous = self.0.vector_clock.clone();
+        self.0.vector_clock.merge(&element.vector_clock);
+
+        if self.0.vector_clock == element.vector_clock {
+            // incoming version is dominating
+            self.0.dependencies.assign(element.dependencies.reveal().clone());
+            self.0.value.assign(element.value.reveal().clone());
+        } else if self.0.vector_clock != previous {
+            // versions are concurrent
+            self.0.dependencies.merge(&element.dependencies);
+            self.0.value.merge(&element.value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Lattice, LWWPairLattice, MaxLattice, PriorityLattice, SingleKeyCausalLattice,
+                SetLattice, VectorClock};
+
+    #[test]
+    fn lww_keeps_latest() {
+        let mut lww = LWWPairLattice::new(2, "later");
+        lww.merge(&LWWPairLattice::new(1, "earlier"));
+        assert_eq!(lww.reveal().value, "later");
+    }
+
+    #[test]
+    fn priority_keeps_lowest() {
+        let mut priority = PriorityLattice::new(2.0, "expensive");
+        priority.merge(&PriorityLattice::new(1.0, "cheap"));
+        assert_eq!(priority.reveal().value, "cheap");
+    }
+
+    #[test]
+    fn causal_dominating_version_replaces() {
+        let mut old_clock = VectorClock::default();
+        old_clock.insert("client".into(), &MaxLattice::new(1));
+        let mut new_clock = VectorClock::default();
+        new_clock.insert("client".into(), &MaxLattice::new(2));
+
+        let mut old_value = SetLattice::default();
+        old_value.insert("old");
+        let mut new_value = SetLattice::default();
+        new_value.insert("new");
+
+        let mut causal = SingleKeyCausalLattice::new(old_clock, old_value);
+        causal.merge(&SingleKeyCausalLattice::new(new_clock, new_value.clone()));
+        assert_eq!(causal.reveal().value, new_value);
+    }
+}
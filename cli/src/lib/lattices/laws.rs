@@ -0,0 +1,232 @@
+//! Laws module provides property based checks that a `Lattice` implementation obeys the laws
+//! that `anna` relies on to merge updates without coordination: `merge` must be associative,
+//! commutative and idempotent, and `merge` and `assign` must only ever move up the lattice.
+//!
+//! Use `check` with a generator of random lattices to verify a custom lattice, e.g. in a test:
+//! ```
+//! use annalib::lattices::{laws, MaxLattice};
+//! use rand::Rng;
+//!
+//! laws::check(|rng| MaxLattice::new(rng.gen_range(0..100u32)), 1000)
+//!     .expect("MaxLattice broke a lattice law");
+//! ```
+use std::fmt::Debug;
+
+use rand::SeedableRng;
+use rand_pcg::Pcg64;
+
+use crate::errors::*;
+use crate::lattices::Lattice;
+
+// Fixed seed so that any counter-example found can be reproduced
+const SEED: u64 = 0x616e6e61;
+
+/*
+    Return a copy of `a` with `b` merged into it
+ */
+fn merged<L: Lattice + Clone>(a: &L, b: &L) -> L {
+    let mut result = a.clone();
+    result.merge(b);
+    result
+}
+
+/// Return true if `a <= b` in the partial order defined by merge, i.e. merging `a` into `b`
+/// leaves `b` unchanged
+pub fn less_or_equal<L: Lattice + Clone + PartialEq>(a: &L, b: &L) -> bool {
+    merged(b, a) == *b
+}
+
+/// Return true if `(a merge b) merge c == a merge (b merge c)`
+pub fn associative<L: Lattice + Clone + PartialEq>(a: &L, b: &L, c: &L) -> bool {
+    merged(&merged(a, b), c) == merged(a, &merged(b, c))
+}
+
+/// Return true if `a merge b == b merge a`
+pub fn commutative<L: Lattice + Clone + PartialEq>(a: &L, b: &L) -> bool {
+    merged(a, b) == merged(b, a)
+}
+
+/// Return true if `a merge a == a`
+pub fn idempotent<L: Lattice + Clone + PartialEq>(a: &L) -> bool {
+    merged(a, a) == *a
+}
+
+/// Return true if merging and assigning are monotonic for `a <= b`:
+/// - `a merge b` is greater than or equal to both `a` and `b`
+/// - `a merge c <= b merge c`
+/// - assigning `b` to `a` gives the same result as merging `b` into `a`
+///
+/// If `a <= b` does not hold only the first condition is checked
+pub fn monotonic<L>(a: &L, b: &L, c: &L) -> bool
+    where L: Lattice + Clone + PartialEq, L::Element: Clone {
+    let joined = merged(a, b);
+    if !less_or_equal(a, &joined) || !less_or_equal(b, &joined) {
+        return false;
+    }
+
+    if !less_or_equal(a, b) {
+        return true;
+    }
+
+    let mut assigned = a.clone();
+    assigned.assign(b.reveal().clone());
+
+    less_or_equal(&merged(a, c), &merged(b, c)) && assigned == joined
+}
+
+/// Check all the lattice laws for `iterations` sets of random lattices created by `generate`,
+/// returning an error describing the first counter-example found
+pub fn check<L, G>(mut generate: G, iterations: usize) -> Result<()>
+    where L: Lattice + Clone + PartialEq + Debug, L::Element: Clone, G: FnMut(&mut Pcg64) -> L {
+    let mut rng = Pcg64::seed_from_u64(SEED);
+
+    for _ in 0..iterations {
+        let a = generate(&mut rng);
+        let b = generate(&mut rng);
+        let c = generate(&mut rng);
+
+        if !associative(&a, &b, &c) {
+            bail!("merge is not associative for {:?}, {:?} and {:?}", a, b, c);
+        }
+
+        if !commutative(&a, &b) {
+            bail!("merge is not commutative for {:?} and {:?}", a, b);
+        }
+
+        if !idempotent(&a) {
+            bail!("merge is not idempotent for {:?}", a);
+        }
+
+        // `a merge b` is always greater than or equal to `a`, so exercises the ordered case
+        if !monotonic(&a, &b, &c) || !monotonic(&a, &merged(&a, &b), &c) {
+            bail!("merge or assign is not monotonic for {:?}, {:?} and {:?}", a, b, c);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{BTreeSet, HashMap};
+
+    use rand::Rng;
+    use rand_pcg::Pcg64;
+
+    use super::check;
+    use crate::lattices::{BoolLattice, Lattice, LWWPairLattice, MapLattice, MaxLattice,
+                          MultiKeyCausalLattice, OrderedSetLattice, PriorityLattice,
+                          PriorityValuePair, SetLattice, SingleKeyCausalLattice, VectorClock};
+
+    const ITERATIONS: usize = 500;
+
+    fn set(rng: &mut Pcg64) -> SetLattice<u8> {
+        SetLattice::new((0..rng.gen_range(0..4)).map(|_| rng.gen_range(0..8)).collect())
+    }
+
+    // A version of a causal key includes every write in its causal past, so the value of a
+    // well-formed causal lattice is determined by its vector clock
+    fn causal_history(rng: &mut Pcg64) -> (VectorClock, SetLattice<String>) {
+        let mut clock = VectorClock::default();
+        let mut history = SetLattice::default();
+        for id in &["a", "b", "c"] {
+            let version = rng.gen_range(0..4);
+            if version > 0 {
+                clock.insert(id.to_string(), &MaxLattice::new(version));
+            }
+            for write in 1..=version {
+                history.insert(format!("{}:{}", id, write));
+            }
+        }
+        (clock, history)
+    }
+
+    #[test]
+    fn bool_lattice() {
+        check(|rng| BoolLattice::new(rng.gen()), ITERATIONS).expect("BoolLattice");
+    }
+
+    #[test]
+    fn max_lattice() {
+        check(|rng| MaxLattice::new(rng.gen_range(0..10)), ITERATIONS).expect("MaxLattice");
+    }
+
+    #[test]
+    fn set_lattice() {
+        check(set, ITERATIONS).expect("SetLattice");
+    }
+
+    #[test]
+    fn ordered_set_lattice() {
+        check(|rng| OrderedSetLattice::new(set(rng).reveal().iter().cloned().collect::<BTreeSet<u8>>()),
+              ITERATIONS).expect("OrderedSetLattice");
+    }
+
+    #[test]
+    fn map_lattice() {
+        check(|rng| {
+            let mut map = HashMap::new();
+            for _ in 0..rng.gen_range(0..4) {
+                map.insert(rng.gen_range(0..4), set(rng));
+            }
+            MapLattice::new(map)
+        }, ITERATIONS).expect("MapLattice");
+    }
+
+    // Few distinct timestamps and priorities, so that values often tie
+    #[test]
+    fn lww_pair_lattice() {
+        check(|rng| LWWPairLattice::new(rng.gen_range(0..4), rng.gen_range(0..4)), ITERATIONS)
+            .expect("LWWPairLattice");
+    }
+
+    #[test]
+    fn priority_lattice() {
+        check(|rng| PriorityLattice::new(rng.gen_range(0..4) as f64, rng.gen_range(0..4)), ITERATIONS)
+            .expect("PriorityLattice");
+        assert_eq!(PriorityValuePair::<String>::default().priority, f64::MAX);
+    }
+
+    #[test]
+    fn single_key_causal_lattice() {
+        check(|rng| {
+            let (clock, value) = causal_history(rng);
+            SingleKeyCausalLattice::new(clock, value)
+        }, ITERATIONS).expect("SingleKeyCausalLattice");
+    }
+
+    #[test]
+    fn multi_key_causal_lattice() {
+        check(|rng| {
+            let (clock, value) = causal_history(rng);
+            let mut dependencies = MapLattice::default();
+            dependencies.insert("dependency".to_string(), &clock);
+            MultiKeyCausalLattice::new(clock, dependencies, value)
+        }, ITERATIONS).expect("MultiKeyCausalLattice");
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct MinusLattice(i32);
+
+    // Subtraction is neither commutative nor idempotent
+    impl Lattice for MinusLattice {
+        type Element = i32;
+
+        fn reveal(&self) -> &i32 {
+            &self.0
+        }
+
+        fn assign(&mut self, element: i32) {
+            self.0 = element;
+        }
+
+        fn merge_element(&mut self, element: &i32) {
+            self.0 -= element;
+        }
+    }
+
+    #[test]
+    fn broken_lattice_is_detected() {
+        assert!(check(|rng| MinusLattice(rng.gen_range(1..10)), ITERATIONS).is_err());
+    }
+}
@@ -0,0 +1,132 @@
+//! Leaderboard module ranks items by score without coordination between writers, for top-K
+//! queries such as leaderboards.
+//!
+//! The best score of each item is kept in a PRIORITY lattice key of its own. The PRIORITY
+//! lattice keeps the value with the lowest priority, so scores are stored negated. The items are
+//! recorded in a SET lattice key, so the top K items are found by reading the PRIORITY keys of
+//! all the items in a batch. The work grows with the number of items, not of scores recorded.
+use std::cmp::Ordering;
+
+use log::debug;
+
+use crate::errors::*;
+use crate::kvs_client::{decode_tuple, default_if_missing, KVSClient, Key};
+use crate::proto::anna::{LatticeType, PriorityValue};
+
+// The number of items shown by ZTOP if no count is given
+const DEFAULT_TOP: usize = 10;
+
+/// `Leaderboard` ranks items by their highest score, stored in `anna` under `key`
+pub struct Leaderboard {
+    key: Key,
+}
+
+impl Leaderboard {
+    /// Create a leaderboard stored under `key`
+    pub fn new(key: &str) -> Self {
+        Leaderboard { key: key.into() }
+    }
+
+    /// Record `score` for `item`. An item keeps the highest score recorded for it.
+    pub fn insert(&self, client: &mut KVSClient, score: f64, item: &str) -> Result<()> {
+        if score.is_nan() {
+            bail!("The score of item '{}' cannot be NaN", item);
+        }
+        client.put_priority_value(&self.item_key(item), -score, item.as_bytes().to_vec())?;
+        client.add_to_set(&self.items_key(), vec!(item.as_bytes().to_vec()))?;
+        client.register_key(&self.key)
+    }
+
+    /// Return the best score recorded for `item`, if any
+    pub fn score(&self, client: &mut KVSClient, item: &str) -> Result<Option<f64>> {
+        match client.get_priority_value(&self.item_key(item)) {
+            Ok(priority) => Ok(Some(-priority.priority)),
+            Err(Error(ErrorKind::KeyDoesNotExist(_), _)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Return the `k` items with the highest scores, and their scores, from the highest down
+    pub fn top(&self, client: &mut KVSClient, k: usize) -> Result<Vec<(f64, String)>> {
+        let items = default_if_missing(client.get_set_values(&self.items_key()))?.iter()
+            .map(|item| String::from_utf8_lossy(item).into())
+            .collect::<Vec<String>>();
+        let keys = items.iter().map(|item| self.item_key(item)).collect::<Vec<Key>>();
+        let tuples = client.get_lattices(&keys)?;
+
+        let mut scores = Vec::new();
+        for (item, key) in items.into_iter().zip(keys) {
+            if let Some(tuple) = tuples.get(&key) {
+                let priority: PriorityValue = decode_tuple(tuple, LatticeType::Priority)?;
+                scores.push((-priority.priority, item));
+            }
+        }
+        Ok(top_scores(scores, k))
+    }
+
+    fn item_key(&self, item: &str) -> Key {
+        format!("{}/items/{}", self.key, item)
+    }
+
+    fn items_key(&self) -> Key {
+        format!("{}/items", self.key)
+    }
+}
+
+/*
+    Return the `k` highest of `scores`, from the highest down, ordering items with the same
+    score by name
+ */
+fn top_scores(mut scores: Vec<(f64, String)>, k: usize) -> Vec<(f64, String)> {
+    scores.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal).then_with(|| a.1.cmp(&b.1)));
+    scores.truncate(k);
+    scores
+}
+
+impl KVSClient {
+    /*
+        ZADD <key> <score> <item>
+     */
+    pub fn zadd(&mut self, tokens: &[&str]) -> Result<String> {
+        debug!("ZADD: {:?}", tokens);
+        if tokens.len() < 3 {
+            bail!("Usage: ZADD <key> <score> <item>");
+        }
+        let score = tokens[1].parse()
+            .chain_err(|| format!("'{}' is not a valid score", tokens[1]))?;
+        Leaderboard::new(tokens[0]).insert(self, score, &tokens[2..].join(" "))?;
+        Ok("Success!".into())
+    }
+
+    /*
+        ZTOP <key> [<k>]
+
+        Each item is displayed on its own line, from the highest score down
+     */
+    pub fn ztop(&mut self, tokens: &[&str]) -> Result<String> {
+        debug!("ZTOP: {:?}", tokens);
+        let key = tokens.first().ok_or("Usage: ZTOP <key> [<k>]")?;
+        let k = match tokens.get(1) {
+            Some(k) => k.parse().chain_err(|| format!("'{}' is not a valid number of items", k))?,
+            None => DEFAULT_TOP,
+        };
+        Ok(Leaderboard::new(key).top(self, k)?.iter()
+            .map(|(score, item)| format!("{} : {}", score, item))
+            .collect::<Vec<String>>()
+            .join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::top_scores;
+
+    #[test]
+    fn top_items_are_the_highest_scores() {
+        let scores = vec!((10.0, "alice".to_string()), (30.0, "bob".to_string()),
+                          (5.0, "carol".to_string()), (10.0, "aaron".to_string()));
+        assert_eq!(top_scores(scores.clone(), 3),
+                   vec!((30.0, "bob".to_string()), (10.0, "aaron".to_string()), (10.0, "alice".to_string())));
+        assert_eq!(top_scores(scores, 10).len(), 4);
+    }
+}
@@ -18,6 +18,29 @@ pub mod info;
 // mod proto;
 pub mod kvs_client;
 pub mod config;
+pub mod session;
+pub mod lattices;
+pub mod codec;
+pub mod renderers;
+pub mod display;
+pub mod crdt;
+pub mod tombstone;
+pub mod expiry;
+pub mod document;
+pub mod index;
+pub mod catalog;
+pub mod event_log;
+pub mod leaderboard;
+pub mod graph;
+pub mod chunk;
+pub mod compression;
+pub mod encryption;
+pub mod ramp;
+pub mod journal;
+pub mod versions;
+pub mod schema;
+pub mod json_path;
+pub mod envelope;
 mod threads;
 pub mod proto;
 
@@ -33,7 +56,18 @@ const BINARY_FOLDER: &str = "build/target/kvs";
 #[doc(hidden)]
 pub mod errors {
     // Create the Error, ErrorKind, ResultExt, and Result types
-    error_chain! {}
+    error_chain! {
+        errors {
+            KeyDoesNotExist(key: String) {
+                description("key does not exist")
+                display("Key '{}' does not exist", key)
+            }
+            ClusterUnreachable(reason: String) {
+                description("cluster is unreachable")
+                display("The cluster is unreachable: {}", reason)
+            }
+        }
+    }
 }
 
 pub use errors::*;
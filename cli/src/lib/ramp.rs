@@ -0,0 +1,277 @@
+//! Ramp module makes writes to a group of keys visible atomically, using the RAMP-Fast
+//! algorithm from "Scalable Atomic Visibility with RAMP Transactions" (Bailis et al.), without
+//! locks or coordination between clients.
+//!
+//! Each write of a transaction stores a record of the value, the transaction's timestamp and
+//! version, and the other keys written by the transaction (its siblings). The records are first
+//! written to versioned keys, one per key and version, and only then to the keys themselves as
+//! LWW values with the transaction's timestamp. A reader reads all of its keys in a batch, and
+//! if a record read names a sibling whose version read is older than the record's, the read was
+//! fractured and the missing versions are fetched from the versioned keys in a second batch.
+//!
+//! Versioned keys are never removed, as `anna` cannot delete keys.
+use std::collections::{BTreeMap, HashMap};
+
+use log::debug;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::errors::*;
+use crate::kvs_client::{decode_tuple, KVSClient, Key, CLIENT_METADATA_PREFIX};
+use crate::proto::anna::{LatticeType, LwwValue};
+use crate::envelope::{strip_plain, tag_plain};
+use crate::expiry::{has_expired, split_expiry};
+use crate::tombstone::is_tombstone;
+
+// Marks the start of a record written by a transaction, which follows it encoded with bincode
+const RECORD_MAGIC: &[u8] = b"\xA7R";
+
+/// The record of the value written to a key by a transaction
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RampRecord {
+    /// The timestamp of the transaction
+    pub timestamp: u64,
+    /// The unique id of the transaction, ordering transactions with the same timestamp
+    pub version: String,
+    /// The other keys written by the transaction
+    pub siblings: Vec<Key>,
+    /// The value written
+    pub value: Vec<u8>,
+}
+
+impl RampRecord {
+    /*
+        The order of the transactions records were written by
+     */
+    fn id(&self) -> (u64, &str) {
+        (self.timestamp, &self.version)
+    }
+}
+
+fn encode_record(record: &RampRecord) -> Result<Vec<u8>> {
+    let mut bytes = RECORD_MAGIC.to_vec();
+    bincode::serialize_into(&mut bytes, record).chain_err(|| "Could not encode transaction record")?;
+    Ok(bytes)
+}
+
+/// Return the record held in the stored `bytes` of a value, if it was written by a transaction
+pub fn decode_record(bytes: &[u8]) -> Result<Option<RampRecord>> {
+    if !bytes.starts_with(RECORD_MAGIC) {
+        return Ok(None);
+    }
+    bincode::deserialize(&bytes[RECORD_MAGIC.len()..]).map(Some)
+        .chain_err(|| "Could not decode transaction record")
+}
+
+fn version_key(key: &str, version: &str) -> Key {
+    format!("{}ramp|{}|{}", CLIENT_METADATA_PREFIX, key, version)
+}
+
+/*
+    Return the (timestamp, version) of the newer version of each of `keys` that the siblings of
+    the records `read` show was written by the same transaction, if the version read is older.
+    Keys that were not written by a transaction are read with an empty version.
+ */
+fn repairs(keys: &[Key], read: &HashMap<Key, RampRecord>) -> BTreeMap<Key, (u64, String)> {
+    let mut latest: HashMap<&str, (u64, &str)> = HashMap::new();
+    for record in read.values() {
+        for sibling in &record.siblings {
+            let entry = latest.entry(sibling).or_insert_with(|| record.id());
+            *entry = (*entry).max(record.id());
+        }
+    }
+
+    keys.iter()
+        .filter_map(|key| {
+            let newest = *latest.get(key.as_str())?;
+            match read.get(key) {
+                Some(record) if record.id() >= newest => None,
+                _ => Some((key.clone(), (newest.0, newest.1.to_string()))),
+            }
+        })
+        .collect()
+}
+
+impl KVSClient {
+    /// Write all of `writes`, the values of a group of keys, so that readers using `ramp_get`
+    /// see either all of them or none of them. Returns the timestamp of the transaction.
+    pub fn ramp_put(&mut self, writes: &BTreeMap<Key, Vec<u8>>) -> Result<u64> {
+        if writes.is_empty() {
+            bail!("A transaction must write at least one key");
+        }
+        let timestamp = self.next_timestamp();
+        let version = self.generate_unique_id();
+        let records = writes.iter()
+            .map(|(key, value)| (key, RampRecord {
+                timestamp,
+                version: version.clone(),
+                siblings: writes.keys().filter(|sibling| *sibling != key).cloned().collect(),
+                value: tag_plain(value),
+            }))
+            .collect::<Vec<(&Key, RampRecord)>>();
+
+        // prepare: no reader can find these versions until a sibling has been committed
+        for (key, record) in &records {
+            self.put_lww_encoded_at(&version_key(key, &version), encode_record(record)?, timestamp)
+                .chain_err(|| format!("Could not prepare the write of key '{}'", key))?;
+        }
+
+        // commit: the transaction's timestamp orders it against other writes to each key. Keys
+        // are versioned and indexed by the value written, not by its record.
+        for (key, record) in &records {
+            if self.is_versioned(key) {
+                self.put_version(key, record.value.clone(), timestamp)?;
+            }
+            let index_keys = self.get_indexes().updates(key, &record.value);
+            self.put_lww_encoded_at(key, encode_record(record)?, timestamp)
+                .chain_err(|| format!("Could not commit the write of key '{}'", key))?;
+            self.add_to_indexes(key, index_keys)?;
+            self.register_key(key)?;
+        }
+        Ok(timestamp)
+    }
+
+    /// Read the values of `keys`, so that of the keys written by a transaction using `ramp_put`,
+    /// either all or none of its writes are seen. Keys that do not exist are not returned.
+    pub fn ramp_get(&mut self, keys: &[Key]) -> Result<HashMap<Key, Vec<u8>>> {
+        let mut read = self.read_records(keys)?;
+
+        let repairs = repairs(keys, &read);
+        if !repairs.is_empty() {
+            debug!("Repairing fractured read of {:?}", repairs);
+            let version_keys = repairs.iter()
+                .map(|(key, (_, version))| version_key(key, version))
+                .collect::<Vec<Key>>();
+            let mut versions = self.read_records(&version_keys)?;
+            for ((key, _), version_key) in repairs.into_iter().zip(version_keys) {
+                let record = versions.remove(&version_key)
+                    .ok_or_else(|| format!("Version '{}' is missing", version_key))?;
+                read.insert(key, record);
+            }
+        }
+
+        Ok(read.into_iter()
+            .filter(|(_, record)| !is_tombstone(&record.value) && !has_expired(&record.value))
+            .map(|(key, record)| (key, strip_plain(split_expiry(&record.value).1).to_vec()))
+            .collect())
+    }
+
+    /*
+        Read the records of `keys` in a batch. Values not written by a transaction are returned
+        as records with no version or siblings.
+     */
+    fn read_records(&mut self, keys: &[Key]) -> Result<HashMap<Key, RampRecord>> {
+        let tuples = self.get_lattices(keys)?;
+        let mut records = HashMap::new();
+        for (key, tuple) in tuples {
+            let lww: LwwValue = decode_tuple(&tuple, LatticeType::Lww)?;
+            let value = self.decode_stored(lww.value)
+                .chain_err(|| format!("Could not decode the value of key '{}'", key))?;
+            let record = match decode_record(&value)? {
+                Some(record) => record,
+                None => RampRecord { timestamp: lww.timestamp, version: String::new(),
+                                     siblings: Vec::new(), value },
+            };
+            records.insert(key, record);
+        }
+        Ok(records)
+    }
+
+    /*
+        MPUT <key> <value> [<key> <value> ...]
+
+        The values of all the keys become visible to MGET together
+     */
+    pub fn mput(&mut self, tokens: &[&str]) -> Result<String> {
+        debug!("MPUT: {:?}", tokens);
+        if tokens.is_empty() || tokens.len() % 2 == 1 {
+            bail!("Usage: MPUT <key> <value> [<key> <value> ...]");
+        }
+        let mut writes = BTreeMap::new();
+        for pair in tokens.chunks(2) {
+            let value = self.renderer_for(pair[0])?.parse(pair[1])?;
+            writes.insert(pair[0].to_string(), value);
+        }
+        self.ramp_put(&writes)?;
+        Ok("Success!".into())
+    }
+
+    /*
+        MGET <key> [<key> ...]
+
+        Each key is displayed on its own line with its value
+     */
+    pub fn mget(&mut self, tokens: &[&str]) -> Result<String> {
+        debug!("MGET: {:?}", tokens);
+        if tokens.is_empty() {
+            bail!("Usage: MGET <key> [<key> ...]");
+        }
+        let keys = tokens.iter().map(|key| key.to_string()).collect::<Vec<Key>>();
+        let mut values = self.ramp_get(&keys)?;
+        let mut lines = Vec::new();
+        for key in keys {
+            let line = match values.remove(&key) {
+                Some(value) => format!("{} : {}", key, self.renderer_for(&key)?.render(&value)?),
+                None => format!("{} : (none)", key),
+            };
+            lines.push(line);
+        }
+        Ok(lines.join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::{decode_record, encode_record, repairs, RampRecord};
+
+    fn record(timestamp: u64, version: &str, siblings: &[&str]) -> RampRecord {
+        RampRecord {
+            timestamp,
+            version: version.into(),
+            siblings: siblings.iter().map(|sibling| sibling.to_string()).collect(),
+            value: version.as_bytes().to_vec(),
+        }
+    }
+
+    fn keys() -> Vec<String> {
+        vec!("x".into(), "y".into())
+    }
+
+    #[test]
+    fn record_round_trip() {
+        let record = record(42, "t1", &["y"]);
+        assert_eq!(decode_record(&encode_record(&record).expect("encode")).expect("decode"), Some(record));
+        assert_eq!(decode_record(b"plain").expect("decode"), None);
+    }
+
+    #[test]
+    fn complete_reads_need_no_repair() {
+        let mut read = HashMap::new();
+        read.insert("x".to_string(), record(2, "t2", &["y"]));
+        read.insert("y".to_string(), record(2, "t2", &["x"]));
+        assert!(repairs(&keys(), &read).is_empty());
+    }
+
+    #[test]
+    fn fractured_reads_are_repaired() {
+        let mut read = HashMap::new();
+        read.insert("x".to_string(), record(2, "t2", &["y", "z"]));
+        read.insert("y".to_string(), record(1, "t1", &["x"]));
+        let repairs = repairs(&keys(), &read);
+        assert_eq!(repairs.len(), 1);
+        assert_eq!(repairs["y"], (2, "t2".to_string()));
+
+        // a sibling not yet written at all is also repaired
+        read.remove("y");
+        assert_eq!(super::repairs(&keys(), &read)["y"], (2, "t2".to_string()));
+    }
+
+    #[test]
+    fn later_writes_are_not_repaired() {
+        let mut read = HashMap::new();
+        read.insert("x".to_string(), record(1, "t1", &["y"]));
+        read.insert("y".to_string(), record(3, "", &[]));
+        assert!(repairs(&keys(), &read).is_empty());
+    }
+}
@@ -0,0 +1,203 @@
+//! Renderers module provides a registry that maps key patterns to a `Renderer`, which parses
+//! values typed by the user into the bytes stored in a key, and renders stored bytes for display.
+//!
+//! Renderers can be registered programmatically as trait objects, or one of the built-in
+//! renderers ("raw", "json", "hex" or "typed") can be selected for a key pattern in the
+//! `renderers` section of the config file.
+use crate::codec;
+use crate::config::Config;
+use crate::errors::*;
+
+/// `Renderer` converts between the text a user works with and the bytes stored for a key
+pub trait Renderer {
+    /// Render the stored `bytes` as text for display
+    fn render(&self, bytes: &[u8]) -> Result<String>;
+
+    /// Parse `text` entered by the user into the bytes to be stored
+    fn parse(&self, text: &str) -> Result<Vec<u8>>;
+}
+
+/// Stores text as is, and renders stored bytes as (lossy) UTF-8
+pub struct RawRenderer;
+
+impl Renderer for RawRenderer {
+    fn render(&self, bytes: &[u8]) -> Result<String> {
+        Ok(String::from_utf8_lossy(bytes).into())
+    }
+
+    fn parse(&self, text: &str) -> Result<Vec<u8>> {
+        Ok(text.as_bytes().to_vec())
+    }
+}
+
+/// Validates JSON text before storing it, and pretty-prints stored JSON
+pub struct JsonRenderer;
+
+impl Renderer for JsonRenderer {
+    fn render(&self, bytes: &[u8]) -> Result<String> {
+        let value: serde_json::Value = serde_json::from_slice(bytes)
+            .chain_err(|| "Stored value is not valid JSON")?;
+        serde_json::to_string_pretty(&value).chain_err(|| "Could not format JSON")
+    }
+
+    fn parse(&self, text: &str) -> Result<Vec<u8>> {
+        let value: serde_json::Value = serde_json::from_str(text)
+            .chain_err(|| format!("'{}' is not valid JSON", text))?;
+        serde_json::to_vec(&value).chain_err(|| "Could not serialize JSON")
+    }
+}
+
+/// Stores and renders bytes as hexadecimal text
+pub struct HexRenderer;
+
+impl Renderer for HexRenderer {
+    fn render(&self, bytes: &[u8]) -> Result<String> {
+        Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+    }
+
+    fn parse(&self, text: &str) -> Result<Vec<u8>> {
+        if text.len() % 2 == 1 {
+            bail!("Hex value '{}' has an odd number of digits", text);
+        }
+        (0..text.len()).step_by(2)
+            .map(|i| u8::from_str_radix(text.get(i..i + 2).unwrap_or_default(), 16)
+                .chain_err(|| format!("'{}' is not a valid hex value", text)))
+            .collect()
+    }
+}
+
+/// Works with values written by the typed API (see the `codec` module), rendering them as
+/// JSON, and storing JSON text as a JSON encoded typed value
+pub struct TypedRenderer;
+
+impl Renderer for TypedRenderer {
+    fn render(&self, bytes: &[u8]) -> Result<String> {
+        let value: serde_json::Value = codec::decode(bytes)
+            .chain_err(|| "Could not decode typed value (bincode values are not self-describing)")?;
+        serde_json::to_string_pretty(&value).chain_err(|| "Could not format JSON")
+    }
+
+    fn parse(&self, text: &str) -> Result<Vec<u8>> {
+        let value: serde_json::Value = serde_json::from_str(text)
+            .chain_err(|| format!("'{}' is not valid JSON", text))?;
+        codec::encode(&value, codec::Codec::Json)
+    }
+}
+
+/// Return a new instance of the built-in renderer called `name`
+pub fn builtin(name: &str) -> Result<Box<dyn Renderer>> {
+    match name {
+        "raw" => Ok(Box::new(RawRenderer)),
+        "json" => Ok(Box::new(JsonRenderer)),
+        "hex" => Ok(Box::new(HexRenderer)),
+        "typed" => Ok(Box::new(TypedRenderer)),
+        _ => bail!("Unknown renderer '{}'", name),
+    }
+}
+
+/// `RendererRegistry` maps key patterns to the `Renderer` to use for keys that match them.
+/// Patterns may use `*` to match any sequence of characters and `?` to match one character.
+pub struct RendererRegistry {
+    renderers: Vec<(String, Box<dyn Renderer>)>,
+    default: RawRenderer,
+}
+
+impl Default for RendererRegistry {
+    fn default() -> Self {
+        RendererRegistry {
+            renderers: Vec::new(),
+            default: RawRenderer,
+        }
+    }
+}
+
+impl RendererRegistry {
+    /// Create a registry with the built-in renderers selected in the `renderers` section of `config`
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let mut registry = RendererRegistry::default();
+        for (pattern, name) in config.get_renderers() {
+            registry.register(pattern, builtin(name)
+                .chain_err(|| format!("Could not create renderer for pattern '{}'", pattern))?);
+        }
+        Ok(registry)
+    }
+
+    /// Use `renderer` for keys that match `pattern`. Patterns are matched in the order they
+    /// were registered.
+    pub fn register(&mut self, pattern: &str, renderer: Box<dyn Renderer>) {
+        self.renderers.push((pattern.into(), renderer));
+    }
+
+    /// Return the renderer for `key`, or the raw renderer if no pattern matches it
+    pub fn renderer_for(&self, key: &str) -> &dyn Renderer {
+        self.renderers.iter()
+            .find(|(pattern, _)| matches(pattern, key))
+            .map_or(&self.default as &dyn Renderer, |(_, renderer)| renderer.as_ref())
+    }
+}
+
+/*
+    Return true if `key` matches the glob `pattern`
+ */
+pub(crate) fn matches(pattern: &str, key: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<char>>();
+    let key = key.chars().collect::<Vec<char>>();
+    let (mut p, mut k) = (0, 0);
+    // position in the pattern after the last '*', and in the key where that '*' match ended
+    let mut backtrack = None;
+
+    while k < key.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == key[k]) {
+            p += 1;
+            k += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p + 1, k));
+            p += 1;
+        } else if let Some((star_p, star_k)) = backtrack {
+            // let the last '*' match one more character and try again
+            p = star_p;
+            k = star_k + 1;
+            backtrack = Some((star_p, star_k + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod test {
+    use super::{matches, HexRenderer, JsonRenderer, Renderer, RendererRegistry};
+
+    #[test]
+    fn glob_matching() {
+        assert!(matches("orders/*", "orders/42"));
+        assert!(matches("*/status", "orders/42/status"));
+        assert!(matches("user-??", "user-42"));
+        assert!(!matches("user-??", "user-420"));
+        assert!(!matches("orders/*", "customers/42"));
+    }
+
+    #[test]
+    fn first_matching_pattern_is_used() {
+        let mut registry = RendererRegistry::default();
+        registry.register("bin/*", Box::new(HexRenderer));
+        registry.register("*", Box::new(JsonRenderer));
+        assert_eq!(registry.renderer_for("bin/1").render(b"\x01\xff").expect("hex"), "01ff");
+        assert!(registry.renderer_for("doc").render(b"not json").is_err());
+    }
+
+    #[test]
+    fn unmatched_keys_are_raw() {
+        let registry = RendererRegistry::default();
+        assert_eq!(registry.renderer_for("key").parse("value").expect("raw"), b"value");
+    }
+
+    #[test]
+    fn hex_round_trip() {
+        let bytes = HexRenderer.parse("00a1ff").expect("Could not parse hex");
+        assert_eq!(HexRenderer.render(&bytes).expect("Could not render hex"), "00a1ff");
+        assert!(HexRenderer.parse("0g").is_err());
+    }
+}
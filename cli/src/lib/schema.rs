@@ -0,0 +1,256 @@
+//! Schema module checks the values written to keys against a schema registered for their prefix,
+//! so that a value of the wrong type cannot be written to a key by mistake.
+//!
+//! A schema is either a simple type ("int", "float", "json" or "bytes") or a JSON Schema. The
+//! registry of schemas is stored in `anna` as a document under a reserved key, with a field for
+//! each key prefix, so clients share it. Clients load the registry once, when it is first used.
+//! If the cluster is unreachable then, as when writes are being journaled, values are not
+//! checked against schemas until the registry is reloaded, rather than writes failing.
+//!
+//! As a `Schema` is also a `Renderer`, the values typed in CLI commands for a key with a schema
+//! are parsed as its type, which checks them, and stored values are displayed according to it.
+use std::collections::BTreeMap;
+
+use jsonschema::JSONSchema;
+use log::debug;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::document::Document;
+use crate::errors::*;
+use crate::kvs_client::{KVSClient, CLIENT_METADATA_PREFIX};
+use crate::renderers::{HexRenderer, JsonRenderer, Renderer};
+
+/// `Schema` is the type of the values that can be written to a key
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Schema {
+    /// A 64-bit signed integer, stored as text
+    Int,
+    /// A 64-bit floating point number, stored as text
+    Float,
+    /// Any JSON value
+    Json,
+    /// Any bytes, entered and displayed as hex
+    Bytes,
+    /// A JSON value that is valid against a JSON Schema
+    JsonSchema(serde_json::Value),
+}
+
+impl Schema {
+    /// Return the simple type called `name`
+    pub fn from_name(name: &str) -> Result<Schema> {
+        match name {
+            "int" => Ok(Schema::Int),
+            "float" => Ok(Schema::Float),
+            "json" => Ok(Schema::Json),
+            "bytes" => Ok(Schema::Bytes),
+            _ => bail!("Unknown type '{}', expected 'int', 'float', 'json' or 'bytes'", name),
+        }
+    }
+
+    /// Parse a schema given on the command line, either the name of a simple type or a
+    /// JSON Schema
+    pub fn parse_schema(text: &str) -> Result<Schema> {
+        if !text.trim_start().starts_with('{') {
+            return Schema::from_name(text);
+        }
+        let schema = serde_json::from_str(text)
+            .chain_err(|| format!("'{}' is not a valid JSON Schema", text))?;
+        compile(&schema)?;
+        Ok(Schema::JsonSchema(schema))
+    }
+
+    /// Check that the stored `bytes` of a value are of this type
+    pub fn validate(&self, bytes: &[u8]) -> Result<()> {
+        let text = || std::str::from_utf8(bytes).chain_err(|| "Value is not UTF-8 text");
+        match self {
+            Schema::Int => text()?.parse::<i64>().map(|_| ())
+                .chain_err(|| format!("'{}' is not a valid int", String::from_utf8_lossy(bytes))),
+            Schema::Float => text()?.parse::<f64>().map(|_| ())
+                .chain_err(|| format!("'{}' is not a valid float", String::from_utf8_lossy(bytes))),
+            Schema::Json => serde_json::from_slice::<serde_json::Value>(bytes).map(|_| ())
+                .chain_err(|| "Value is not valid JSON"),
+            Schema::Bytes => Ok(()),
+            Schema::JsonSchema(schema) => {
+                let value = serde_json::from_slice(bytes).chain_err(|| "Value is not valid JSON")?;
+                let compiled = compile(schema)?;
+                let result = compiled.validate(&value);
+                if let Err(errors) = result {
+                    let errors = errors.map(|error| error.to_string()).collect::<Vec<String>>();
+                    bail!("Value does not match the JSON Schema: {}", errors.join(", "));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Schema {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Schema::Int => write!(f, "int"),
+            Schema::Float => write!(f, "float"),
+            Schema::Json => write!(f, "json"),
+            Schema::Bytes => write!(f, "bytes"),
+            Schema::JsonSchema(schema) => write!(f, "{}", schema),
+        }
+    }
+}
+
+impl Renderer for Schema {
+    fn render(&self, bytes: &[u8]) -> Result<String> {
+        match self {
+            Schema::Int | Schema::Float => Ok(String::from_utf8_lossy(bytes).into()),
+            Schema::Json | Schema::JsonSchema(_) => JsonRenderer.render(bytes),
+            Schema::Bytes => HexRenderer.render(bytes),
+        }
+    }
+
+    fn parse(&self, text: &str) -> Result<Vec<u8>> {
+        let bytes = match self {
+            Schema::Int => text.parse::<i64>()
+                .chain_err(|| format!("'{}' is not a valid int", text))?.to_string().into_bytes(),
+            Schema::Float => text.parse::<f64>()
+                .chain_err(|| format!("'{}' is not a valid float", text))?.to_string().into_bytes(),
+            Schema::Json | Schema::JsonSchema(_) => JsonRenderer.parse(text)?,
+            Schema::Bytes => HexRenderer.parse(text)?,
+        };
+        self.validate(&bytes)?;
+        Ok(bytes)
+    }
+}
+
+fn compile(schema: &serde_json::Value) -> Result<JSONSchema> {
+    JSONSchema::compile(schema).map_err(|e| format!("Invalid JSON Schema: {}", e).into())
+}
+
+/*
+    The document the registry is stored in, with a field for each key prefix
+ */
+fn registry_document() -> Document {
+    Document::new(&format!("{}schemas", CLIENT_METADATA_PREFIX))
+}
+
+/// `SchemaRegistry` maps key prefixes to the `Schema` of the values of the keys with them
+#[derive(Default)]
+pub struct SchemaRegistry {
+    schemas: BTreeMap<String, Schema>,
+}
+
+impl SchemaRegistry {
+    /// Load the registry stored in `anna`
+    pub fn load(client: &mut KVSClient) -> Result<SchemaRegistry> {
+        let mut schemas = BTreeMap::new();
+        for (prefix, schema) in registry_document().get_all(client)? {
+            let schema = serde_json::from_slice(&schema)
+                .chain_err(|| format!("Could not decode the schema of prefix '{}'", prefix))?;
+            schemas.insert(prefix, schema);
+        }
+        debug!("Loaded {} schemas", schemas.len());
+        Ok(SchemaRegistry { schemas })
+    }
+
+    /// Return the schema of `key`, the one registered for the longest prefix of it
+    pub fn schema_for(&self, key: &str) -> Option<&Schema> {
+        self.schemas.iter()
+            .filter(|(prefix, _)| key.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, schema)| schema)
+    }
+
+    /// Return the prefixes registered, and their schemas
+    pub fn schemas(&self) -> &BTreeMap<String, Schema> {
+        &self.schemas
+    }
+}
+
+impl KVSClient {
+    /// Register `schema` for the keys that start with `prefix`
+    pub fn register_schema(&mut self, prefix: &str, schema: &Schema) -> Result<()> {
+        let bytes = serde_json::to_vec(schema).chain_err(|| "Could not encode schema")?;
+        registry_document().set(self, prefix, bytes)?;
+        self.reload_schemas();
+        Ok(())
+    }
+
+    /// Check the stored `bytes` of a value are valid for the schema of `key`, if it has one
+    pub fn check_schema(&mut self, key: &str, bytes: &[u8]) -> Result<()> {
+        match self.get_schemas()?.schema_for(key) {
+            Some(schema) => schema.validate(bytes)
+                .chain_err(|| format!("Value is not valid for key '{}' of type {}", key, schema)),
+            None => Ok(()),
+        }
+    }
+
+    /*
+        SCHEMA_SET <prefix> <int | float | json | bytes | JSON Schema>
+     */
+    pub fn schema_set(&mut self, tokens: &[&str]) -> Result<String> {
+        debug!("SCHEMA_SET: {:?}", tokens);
+        if tokens.len() < 2 {
+            bail!("Usage: SCHEMA_SET <prefix> <int | float | json | bytes | JSON Schema>");
+        }
+        let schema = Schema::parse_schema(&tokens[1..].join(" "))?;
+        self.register_schema(tokens[0], &schema)?;
+        Ok("Success!".into())
+    }
+
+    /*
+        SCHEMAS
+
+        Each prefix is displayed on its own line with its schema
+     */
+    pub fn list_schemas(&mut self, tokens: &[&str]) -> Result<String> {
+        debug!("SCHEMAS: {:?}", tokens);
+        Ok(self.get_schemas()?.schemas().iter()
+            .map(|(prefix, schema)| format!("{} : {}", prefix, schema))
+            .collect::<Vec<String>>()
+            .join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::{Schema, SchemaRegistry};
+    use crate::renderers::Renderer;
+
+    #[test]
+    fn simple_types() {
+        assert_eq!(Schema::Int.parse("42").expect("int"), b"42");
+        assert!(Schema::Int.parse("forty two").is_err());
+        assert!(Schema::Int.validate(b"4.2").is_err());
+        assert_eq!(Schema::Float.parse("4.50").expect("float"), b"4.5");
+        assert_eq!(Schema::Bytes.parse("00ff").expect("bytes"), vec!(0, 255));
+        assert!(Schema::Json.parse("{\"a\": ").is_err());
+        assert!(Schema::from_name("string").is_err());
+    }
+
+    #[test]
+    fn json_schemas() {
+        let schema = Schema::parse_schema(r#"{"type": "object", "required": ["name"]}"#).expect("schema");
+        assert_eq!(schema, Schema::JsonSchema(json!({"type": "object", "required": ["name"]})));
+        assert!(schema.parse(r#"{"name": "anna"}"#).is_ok());
+        assert!(schema.parse(r#"{"age": 3}"#).is_err());
+        assert!(Schema::parse_schema(r#"{"type": 7}"#).is_err());
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let mut registry = SchemaRegistry::default();
+        registry.schemas.insert("counters/".into(), Schema::Int);
+        registry.schemas.insert("counters/ratios/".into(), Schema::Float);
+        assert_eq!(registry.schema_for("counters/visits"), Some(&Schema::Int));
+        assert_eq!(registry.schema_for("counters/ratios/hit"), Some(&Schema::Float));
+        assert_eq!(registry.schema_for("users/alice"), None);
+    }
+
+    #[test]
+    fn schemas_round_trip() {
+        for schema in &[Schema::Int, Schema::JsonSchema(json!({"type": "string"}))] {
+            let bytes = serde_json::to_vec(schema).expect("encode");
+            assert_eq!(&serde_json::from_slice::<Schema>(&bytes).expect("decode"), schema);
+        }
+    }
+}
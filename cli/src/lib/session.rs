@@ -0,0 +1,132 @@
+//! Session module holds the causal state of a `KVSClient` (client id, vector clock and
+//! dependency set) and allows it to be persisted to a file between invocations of the CLI
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::errors::*;
+use crate::kvs_client::Key;
+
+/// A `VectorClock` maps a client id to the latest version seen from that client
+pub type VectorClock = HashMap<String, u32>;
+
+/// `Session` contains the causal history of a client
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Session {
+    client_id: String,
+    vector_clock: VectorClock,
+    dependencies: HashMap<Key, VectorClock>,
+}
+
+impl Session {
+    /// Create a new, empty, `Session` for the client with id `client_id`
+    pub fn new(client_id: &str) -> Self {
+        Session {
+            client_id: client_id.into(),
+            vector_clock: VectorClock::new(),
+            dependencies: HashMap::new(),
+        }
+    }
+
+    /// Load a `Session` from a yaml session file
+    pub fn load(filename: &str) -> Result<Session> {
+        let mut file = File::open(filename)
+            .chain_err(|| format!("Could not open session file '{}'", filename))?;
+        let mut content = String::new();
+        file.read_to_string(&mut content)
+            .chain_err(|| format!("Could not read content from '{}'", filename))?;
+        serde_yaml::from_str(&content)
+            .chain_err(|| format!("Error deserializing session from: '{}'", filename))
+    }
+
+    /// Save the `Session` to a yaml session file, replacing any previous contents
+    pub fn save(&self, filename: &str) -> Result<()> {
+        let content = serde_yaml::to_string(self)
+            .chain_err(|| "Error serializing session")?;
+        let mut file = File::create(filename)
+            .chain_err(|| format!("Could not create session file '{}'", filename))?;
+        file.write_all(content.as_bytes())
+            .chain_err(|| format!("Could not write session to '{}'", filename))
+    }
+
+    /// Return the id of the client that owns this session
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    /// Return the current vector clock of the session
+    pub fn vector_clock(&self) -> &VectorClock {
+        &self.vector_clock
+    }
+
+    /// Return the set of keys (and the versions of them) that this session depends on
+    pub fn dependencies(&self) -> &HashMap<Key, VectorClock> {
+        &self.dependencies
+    }
+
+    /// Advance this client's entry in the vector clock, ready for a new write,
+    /// and return the resulting vector clock
+    pub fn tick(&mut self) -> VectorClock {
+        *self.vector_clock.entry(self.client_id.clone()).or_insert(0) += 1;
+        self.vector_clock.clone()
+    }
+
+    /// Record that the session has observed version `vector_clock` of `key`. Later writes
+    /// will declare a dependency on it, and the session's own clock advances past it.
+    pub fn observe(&mut self, key: &str, vector_clock: &VectorClock) {
+        merge_clocks(self.dependencies.entry(key.into()).or_default(), vector_clock);
+        merge_clocks(&mut self.vector_clock, vector_clock);
+    }
+}
+
+/*
+    Merge `other` into `clock` taking the maximum version for each client id
+ */
+fn merge_clocks(clock: &mut VectorClock, other: &VectorClock) {
+    for (id, version) in other {
+        let entry = clock.entry(id.clone()).or_insert(0);
+        if *entry < *version {
+            *entry = *version;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Session, VectorClock};
+
+    #[test]
+    fn tick_advances_own_clock() {
+        let mut session = Session::new("client");
+        session.tick();
+        assert_eq!(session.tick().get("client"), Some(&2));
+    }
+
+    #[test]
+    fn observe_merges_clocks() {
+        let mut session = Session::new("client");
+        session.tick();
+        let mut observed = VectorClock::new();
+        observed.insert("client".into(), 0);
+        observed.insert("other".into(), 3);
+        session.observe("key", &observed);
+
+        assert_eq!(session.vector_clock().get("client"), Some(&1));
+        assert_eq!(session.vector_clock().get("other"), Some(&3));
+        assert_eq!(session.dependencies().get("key"), Some(&observed));
+    }
+
+    #[test]
+    fn save_and_load() {
+        let mut session = Session::new("client");
+        session.tick();
+        let path = std::env::temp_dir().join("anna_session_save_and_load.yml");
+        let filename = path.to_str().expect("Could not convert temp path");
+        session.save(filename).expect("Could not save session");
+        let loaded = Session::load(filename).expect("Could not load session");
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(loaded, session);
+    }
+}
@@ -62,6 +62,12 @@ impl UserThread {
 // UserRoutingThread
 pub type UserRoutingThread = UserThread;
 
+impl UserRoutingThread {
+    pub fn key_address_request_connect_address(&self) -> Address {
+        format!("{}{}", self.ip_base, self.tid + K_KEY_ADDRESS_PORT)
+    }
+}
+
 // CacheThread
 pub type CacheThread = Thread;
 
@@ -0,0 +1,172 @@
+//! Tombstone module supports deleting keys. `anna` has no DELETE request, so a key is deleted
+//! by writing a tombstone marker to its LWW lattice with a fresh timestamp. Reads of LWW values
+//! treat a tombstoned key as one that does not exist, and a later PUT brings the key back.
+//!
+//! Deleted keys are recorded in a catalog, an observed-remove set stored under a reserved key,
+//! so that they can be found again by the sweeper, and left out when listing keys.
+//!
+//! Tombstones are written directly to the LWW lattice of the key, encrypted if encryption is
+//! enabled, so they are not added to indexes. They are recorded as versions of versioned keys,
+//! so the history of a key shows when it was deleted.
+use std::collections::HashSet;
+
+use log::debug;
+
+use crate::crdt::ORSet;
+use crate::errors::*;
+use crate::kvs_client::{decode_tuple, KVSClient, CLIENT_METADATA_PREFIX};
+use crate::proto::anna::{KeyTuple, LatticeType, LwwValue};
+
+// The value written to the LWW lattice of a deleted key. Values written by users are stored
+// after the plain value header, so they cannot be confused with it.
+const TOMBSTONE_MARKER: &[u8] = b"\xA7D";
+
+/// Return true if `value` is the tombstone marker written by a delete
+pub fn is_tombstone(value: &[u8]) -> bool {
+    value == TOMBSTONE_MARKER
+}
+
+/// Return true if `tuple` holds an LWW lattice that has been deleted
+pub fn is_tombstoned(tuple: &KeyTuple) -> bool {
+    tuple.lattice_type() == LatticeType::Lww &&
+        decode_tuple::<LwwValue>(tuple, LatticeType::Lww)
+            .map(|lww| is_tombstone(&lww.value))
+            .unwrap_or(false)
+}
+
+/*
+    The catalog of keys that have been deleted
+ */
+fn catalog() -> ORSet {
+    ORSet::new(&format!("{}tombstones", CLIENT_METADATA_PREFIX))
+}
+
+/// The result of sweeping the catalog of deleted keys
+#[derive(Debug, Default, PartialEq)]
+pub struct SweepReport {
+    /// The keys that are still deleted
+    pub tombstoned: Vec<String>,
+    /// The keys that were written again since being deleted, and were dropped from the catalog
+    pub resurrected: Vec<String>,
+}
+
+impl KVSClient {
+    /// Delete `key` by writing a tombstone to its LWW lattice, returning the timestamp of the
+    /// tombstone. The key is added to the catalog of deleted keys.
+    pub fn delete(&mut self, key: &str) -> Result<u64> {
+        let timestamp = self.next_timestamp();
+        self.put_tombstone_at(key, timestamp)?;
+        self.add_to_catalog(key)?;
+        Ok(timestamp)
+    }
+
+    /// Delete `key` unless it has been written since the write with `timestamp`, by writing a
+    /// tombstone that is ordered just after it
+    pub fn delete_written_at(&mut self, key: &str, timestamp: u64) -> Result<()> {
+        self.put_tombstone_at(key, timestamp + 1)?;
+        self.add_to_catalog(key)
+    }
+
+    fn put_tombstone_at(&mut self, key: &str, timestamp: u64) -> Result<()> {
+        if self.is_versioned(key) {
+            self.put_version(key, TOMBSTONE_MARKER.to_vec(), timestamp)?;
+        }
+        let tombstone = self.encrypt_if_enabled(TOMBSTONE_MARKER.to_vec())?;
+        self.put_lww_at(key, tombstone, timestamp)
+    }
+
+    /*
+        Return true if `key` currently holds a tombstone
+     */
+    fn is_deleted(&mut self, key: &str) -> Result<bool> {
+        match self.get_lattice(key) {
+            Ok(tuple) => Ok(is_tombstoned(&self.decode_stored_tuple(tuple)?)),
+            Err(Error(ErrorKind::KeyDoesNotExist(_), _)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Return those of `keys` that are currently deleted
+    pub fn deleted_keys<'a>(&mut self, keys: impl IntoIterator<Item = &'a String>)
+                            -> Result<HashSet<String>> {
+        let members = catalog().members(self)?;
+        let mut deleted = HashSet::new();
+        for key in keys.into_iter().filter(|key| members.contains(*key)) {
+            if self.is_deleted(key)? {
+                deleted.insert(key.clone());
+            }
+        }
+        Ok(deleted)
+    }
+
+    fn add_to_catalog(&mut self, key: &str) -> Result<()> {
+        catalog().add(self, key)
+            .chain_err(|| format!("Could not add key '{}' to the catalog of deleted keys", key))
+    }
+
+    /// Sweep the catalog of deleted keys, dropping keys that have been written again since they
+    /// were deleted, and compacting the catalog. `anna` cannot reclaim the tombstones themselves,
+    /// but they are the smallest value an LWW lattice can hold.
+    pub fn sweep_tombstones(&mut self) -> Result<SweepReport> {
+        let catalog = catalog();
+        let mut report = SweepReport::default();
+
+        for key in catalog.members(self)? {
+            if self.is_deleted(&key)? {
+                report.tombstoned.push(key);
+            } else {
+                debug!("Key '{}' was written after it was deleted", key);
+                catalog.remove(self, &key)?;
+                report.resurrected.push(key);
+            }
+        }
+
+        catalog.compact(self)?;
+        Ok(report)
+    }
+
+    /*
+        DEL <key>...
+     */
+    pub fn del(&mut self, tokens: &[&str]) -> Result<String> {
+        debug!("DEL: {:?}", tokens);
+        if tokens.is_empty() {
+            bail!("Usage: DEL <key>...");
+        }
+        for key in tokens {
+            self.delete(key)?;
+        }
+        Ok("Success!".into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_tombstone, is_tombstoned, TOMBSTONE_MARKER};
+    use crate::kvs_client::encode;
+    use crate::proto::anna::{KeyTuple, LatticeType, LwwValue, SetValue};
+
+    #[test]
+    fn only_lww_tombstones_are_tombstoned() {
+        let lww = KeyTuple {
+            key: "key".into(),
+            lattice_type: LatticeType::Lww as i32,
+            payload: encode(&LwwValue { timestamp: 1, value: TOMBSTONE_MARKER.to_vec() }),
+            ..Default::default()
+        };
+        assert!(is_tombstoned(&lww));
+
+        let set = KeyTuple {
+            lattice_type: LatticeType::Set as i32,
+            payload: encode(&SetValue { values: vec!(TOMBSTONE_MARKER.to_vec()) }),
+            ..lww
+        };
+        assert!(!is_tombstoned(&set));
+    }
+
+    #[test]
+    fn user_values_are_not_tombstones() {
+        assert!(!is_tombstone(b""));
+        assert!(!is_tombstone(b"deleted"));
+    }
+}
@@ -0,0 +1,208 @@
+//! Versions module keeps the history of the values of "versioned" keys, which LWW lattices
+//! would otherwise discard.
+//!
+//! Each value written to a versioned key is also written to an immutable version key,
+//! `key@<timestamp>`, where the timestamp is that of the LWW write. The versions of a key are
+//! recorded in an ORDERED_SET lattice index, ordered by the time they were written, so the
+//! value of the key at any time can be found without listing keys. Version keys are neither
+//! indexed nor registered in the catalog, so they are not found by index queries or listed.
+//! Deleting a versioned key writes its tombstone as a version too.
+use std::time::Duration;
+
+use log::debug;
+
+use crate::errors::*;
+use crate::envelope::strip_plain;
+use crate::expiry::{now_ms, split_expiry};
+use crate::kvs_client::{decode_tuple, default_if_missing, KVSClient, Key, CLIENT_METADATA_PREFIX};
+use crate::proto::anna::{LatticeType, LwwValue};
+use crate::ramp::decode_record;
+use crate::renderers::matches;
+use crate::tombstone::is_tombstone;
+
+/// A version of the value of a versioned key
+#[derive(Debug, PartialEq)]
+pub struct Version {
+    /// The time the version was written, in ms since the UNIX epoch
+    pub time: u64,
+    /// The timestamp of the LWW write, which names its version key
+    pub timestamp: u64,
+}
+
+impl Version {
+    /// Return the immutable key holding the value of this version of `key`
+    pub fn key(&self, key: &str) -> Key {
+        format!("{}@{}", key, self.timestamp)
+    }
+
+    /*
+        Encode the version as an entry of the index, which sort by the time they were written
+     */
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = self.time.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Version> {
+        if bytes.len() != 16 {
+            bail!("Version index entry of {} bytes is not 16 bytes long", bytes.len());
+        }
+        let mut time = [0; 8];
+        let mut timestamp = [0; 8];
+        time.copy_from_slice(&bytes[..8]);
+        timestamp.copy_from_slice(&bytes[8..]);
+        Ok(Version { time: u64::from_be_bytes(time), timestamp: u64::from_be_bytes(timestamp) })
+    }
+}
+
+fn index_key(key: &str) -> Key {
+    format!("{}versions|{}", CLIENT_METADATA_PREFIX, key)
+}
+
+/// Parse a time given on the command line, either in ms since the UNIX epoch or relative to
+/// `now` as a number of seconds, minutes, hours or days ago, such as "-90s" or "-1h"
+pub fn parse_time(time: &str, now: u64) -> Result<u64> {
+    let ago = match time.strip_prefix('-') {
+        Some(ago) => ago,
+        None => return time.parse().chain_err(|| format!("'{}' is not a valid time", time)),
+    };
+
+    let split = ago.char_indices().last().map_or(0, |(index, _)| index);
+    let count: u64 = ago[..split].parse().chain_err(|| format!("'{}' is not a valid time", time))?;
+    let unit = match &ago[split..] {
+        "s" => Duration::from_secs(1),
+        "m" => Duration::from_secs(60),
+        "h" => Duration::from_secs(60 * 60),
+        "d" => Duration::from_secs(24 * 60 * 60),
+        _ => bail!("'{}' is not a valid time, expected a unit of s, m, h or d", time),
+    };
+    let ago = count.checked_mul(unit.as_millis() as u64)
+        .ok_or_else(|| format!("'{}' is too long ago", time))?;
+    Ok(now.saturating_sub(ago))
+}
+
+/*
+    Return the last of the ordered `versions` written at or before `time`
+ */
+fn version_at(versions: &[Version], time: u64) -> Option<&Version> {
+    versions.iter().take_while(|version| version.time <= time).last()
+}
+
+impl KVSClient {
+    /// Keep the history of the values of keys that match the glob `pattern`
+    pub fn add_versioned_pattern(&mut self, pattern: &str) {
+        self.get_versioned_patterns_mut().push(pattern.into());
+    }
+
+    /// Return true if the history of the values of `key` is kept
+    pub fn is_versioned(&self, key: &str) -> bool {
+        !key.starts_with(CLIENT_METADATA_PREFIX) &&
+            self.get_versioned_patterns().iter().any(|pattern| matches(pattern, key))
+    }
+
+    /*
+        Write `value`, written to `key` with `timestamp`, to its version key and record the
+        version in the version index
+     */
+    pub(crate) fn put_version(&mut self, key: &str, value: Vec<u8>, timestamp: u64) -> Result<()> {
+        let version = Version { time: now_ms(), timestamp };
+        self.put_lww_encoded_at(&version.key(key), value, timestamp)
+            .chain_err(|| format!("Could not write version {} of key '{}'", timestamp, key))?;
+        self.add_to_ordered_set(&index_key(key), vec!(version.encode()))
+    }
+
+    /// Return the versions of `key`, from the oldest to the newest
+    pub fn versions(&mut self, key: &str) -> Result<Vec<Version>> {
+        default_if_missing(self.get_ordered_set_values(&index_key(key)))?.iter()
+            .map(|entry| Version::decode(entry))
+            .collect()
+    }
+
+    /// Read the value `key` had at `time`, in ms since the UNIX epoch. A key that was deleted, or
+    /// whose value had expired, at `time` does not exist.
+    pub fn get_lww_at(&mut self, key: &str, time: u64) -> Result<LwwValue> {
+        let versions = self.versions(key)?;
+        let version = version_at(&versions, time)
+            .ok_or_else(|| format!("Key '{}' had no value at {}", key, time))?;
+        self.get_lww_as_of(&version.key(key), time)
+            .chain_err(|| format!("Could not read the value key '{}' had at {}", key, time))
+    }
+
+    /*
+        HISTORY <key>
+
+        Each version is displayed on its own line, from the oldest to the newest, with the time
+        it was written in ms since the UNIX epoch
+     */
+    pub fn history(&mut self, tokens: &[&str]) -> Result<String> {
+        debug!("HISTORY: {:?}", tokens);
+        let key = tokens.first().ok_or("Usage: HISTORY <key>")?;
+        let versions = self.versions(key)?;
+        let version_keys = versions.iter().map(|version| version.key(key)).collect::<Vec<Key>>();
+        let tuples = self.get_lattices(&version_keys)?;
+
+        let mut lines = Vec::new();
+        for (version, version_key) in versions.iter().zip(version_keys) {
+            let value = match tuples.get(&version_key) {
+                Some(tuple) => {
+                    let lww: LwwValue = decode_tuple(tuple, LatticeType::Lww)?;
+                    let value = self.decode_stored(lww.value)?;
+                    match decode_record(&value)? {
+                        Some(record) => record.value,
+                        None => split_expiry(&value).1.to_vec(),
+                    }
+                }
+                None => continue,
+            };
+            let rendered = if is_tombstone(&value) {
+                "(deleted)".into()
+            } else {
+                self.renderer_for(key)?.render(strip_plain(&value))?
+            };
+            lines.push(format!("{} : {}", version.time, rendered));
+        }
+        Ok(lines.join("\n"))
+    }
+
+    /*
+        GET <key> --at <time>
+     */
+    pub(crate) fn get_at(&mut self, key: &str, time: &str) -> Result<String> {
+        let time = parse_time(time, now_ms())?;
+        let value = self.get_lww_at(key, time)?.value;
+        self.renderer_for(key)?.render(&value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_time, version_at, Version};
+
+    #[test]
+    fn version_round_trip() {
+        let version = Version { time: 1_600_000_000_000, timestamp: 16_000_000_000_003 };
+        assert_eq!(Version::decode(&version.encode()).expect("decode"), version);
+        assert_eq!(version.key("config/app"), "config/app@16000000000003");
+        assert!(Version::decode(b"short").is_err());
+    }
+
+    #[test]
+    fn times() {
+        let now = 10_000_000;
+        assert_eq!(parse_time("1234", now).expect("time"), 1234);
+        assert_eq!(parse_time("-90s", now).expect("time"), now - 90_000);
+        assert_eq!(parse_time("-1h", now).expect("time"), now - 3_600_000);
+        assert!(parse_time("-1y", now).is_err());
+        assert!(parse_time("yesterday", now).is_err());
+        assert!(parse_time("-18446744073709551615d", now).is_err());
+    }
+
+    #[test]
+    fn latest_version_at_a_time() {
+        let versions = [Version { time: 10, timestamp: 100 }, Version { time: 20, timestamp: 200 }];
+        assert_eq!(version_at(&versions, 5), None);
+        assert_eq!(version_at(&versions, 10).map(|version| version.timestamp), Some(100));
+        assert_eq!(version_at(&versions, 25).map(|version| version.timestamp), Some(200));
+    }
+}
@@ -10,11 +10,11 @@ extern crate error_chain;
 use std::env;
 use std::process::exit;
 
-use clap::{App, Arg, SubCommand, ArgMatches};
+use clap::{App, AppSettings, Arg, SubCommand, ArgMatches};
 use rustyline::Editor;
 use log::{debug, warn, info};
 use simplog::simplog::SimpleLogger;
-use annalib::{info, start, stop, kvs_client::KVSClient, config::Config};
+use annalib::{info, start, stop, kvs_client::{check_user_key, KVSClient}, config::Config};
 use std::fs::File;
 use std::io::{BufReader, BufRead};
 
@@ -85,35 +85,99 @@ fn run() -> Result<String> {
     let config = Config::read(&config_file)
         .chain_err(|| format!("Could not read config file: {}", config_file))?;
 
-    let kvs_client = KVSClient::new(&config, None);
+    let namespace = matches.value_of("namespace");
 
     match matches.subcommand() {
         ("help", _) => help(app_clone),
         ("start", _) => Ok(format!("{} anna processes were started", start(&config)?)),
         ("stop", _) => Ok(format!("{} anna processes were terminated", stop()?)),
-        ("cli", None) => Ok(cli_loop_interactive(kvs_client)?.into()),
-        ("cli", Some(args)) => Ok(cli_loop(kvs_client, args)?.into()),
+        ("cli", args) => Ok(cli(&config, namespace, args)?.into()),
+        ("sweep", _) => sweep(&config, namespace),
+        ("expire-sweep", _) => expire_sweep(&config, namespace),
+        ("keys", args) => keys(&config, namespace, args),
+        ("journal", Some(args)) => journal(&config, namespace, args),
         (_, _) => Ok("No command executed".into())
     }
 }
 
-fn execute_command(line: &str, client: &KVSClient) {
+/*
+    Return the keys given to `command`, which must not be the client's own metadata keys
+*/
+fn command_keys<'a>(command: &str, tokens: &[&'a str]) -> Vec<&'a str> {
+    match command {
+        "DEL" | "MGET" => tokens.to_vec(),
+        "MPUT" => tokens.iter().step_by(2).copied().collect(),
+        "INDEX_REBUILD" => tokens.iter().skip(1).copied().collect(),
+        "INDEX_QUERY" | "SCHEMAS" => Vec::new(),
+        _ => tokens.first().copied().into_iter().collect(),
+    }
+}
+
+fn execute_command(line: &str, client: &mut KVSClient) {
     let split = line.split(' ').collect::<Vec<&str>>();
-    match (split[0].to_ascii_uppercase().as_str(), &split[1..]) {
+    let command = split[0].to_ascii_uppercase();
+    if let Err(e) = command_keys(&command, &split[1..]).into_iter().try_for_each(check_user_key) {
+        eprintln!("error: {}", e);
+        return;
+    }
+    let result = match (command.as_str(), &split[1..]) {
         ("GET", tokens) => client.get(tokens),
+        ("INSPECT", tokens) => client.inspect(tokens),
         ("GET_CAUSAL", tokens) => client.get_causal(tokens),
         ("PUT", tokens) => client.put(tokens),
         ("PUT_CAUSAL", tokens) => client.put_causal(tokens),
         ("PUT_SET", tokens) => client.put_set(tokens),
         ("GET_SET", tokens) => client.get_set(tokens),
-        (command, _) => eprintln!("Unrecognized anna command: {}. Was ignored.", command),
+        ("PUT_ORDERED", tokens) => client.put_ordered(tokens),
+        ("GET_ORDERED", tokens) => client.get_ordered(tokens),
+        ("PUT_PRIORITY", tokens) => client.put_priority(tokens),
+        ("GET_PRIORITY", tokens) => client.get_priority(tokens),
+        ("INCR", tokens) => client.incr(tokens),
+        ("DECR", tokens) => client.decr(tokens),
+        ("COUNT", tokens) => client.count(tokens),
+        ("SADD", tokens) => client.sadd(tokens),
+        ("SREM", tokens) => client.srem(tokens),
+        ("SMEMBERS", tokens) => client.smembers(tokens),
+        ("SCOMPACT", tokens) => client.scompact(tokens),
+        ("DEL", tokens) => client.del(tokens),
+        ("PUT_TTL", tokens) => client.put_ttl(tokens),
+        ("HSET", tokens) => client.hset(tokens),
+        ("HGET", tokens) => client.hget(tokens),
+        ("HGETALL", tokens) => client.hgetall(tokens),
+        ("INDEX_QUERY", tokens) => client.index_query(tokens),
+        ("INDEX_REBUILD", tokens) => client.index_rebuild(tokens),
+        ("KEYS", tokens) => client.keys(tokens),
+        ("APPEND", tokens) => client.append(tokens),
+        ("RANGE", tokens) => client.range(tokens),
+        ("TAIL", tokens) => client.tail(tokens),
+        ("LOG_COMPACT", tokens) => client.log_compact(tokens),
+        ("ZADD", tokens) => client.zadd(tokens),
+        ("ZTOP", tokens) => client.ztop(tokens),
+        ("EDGE_ADD", tokens) => client.edge_add(tokens),
+        ("NEIGHBORS", tokens) => client.neighbors(tokens),
+        ("KHOP", tokens) => client.khop(tokens),
+        ("MPUT", tokens) => client.mput(tokens),
+        ("MGET", tokens) => client.mget(tokens),
+        ("HISTORY", tokens) => client.history(tokens),
+        ("SCHEMA_SET", tokens) => client.schema_set(tokens),
+        ("SCHEMAS", tokens) => client.list_schemas(tokens),
+        ("PATCH", tokens) => client.patch(tokens),
+        (command, _) => {
+            eprintln!("Unrecognized anna command: {}. Was ignored.", command);
+            return;
+        }
+    };
+
+    match result {
+        Ok(output) => println!("{}", output),
+        Err(e) => eprintln!("error: {}", e),
     }
 }
 
 /*
     Enter a loop of command/response for the CLI and interact with the server processes for each
 */
-fn cli_loop_interactive(client: KVSClient) -> Result<&'static str> {
+fn cli_loop_interactive(client: &mut KVSClient) -> Result<&'static str> {
     let mut rl = Editor::<()>::new(); // `()` can be used when no completer is required
     if rl.load_history(ANNA_HISTORY_FILENAME).is_err() {
         println!("No previous history. Saving new history in {}", ANNA_HISTORY_FILENAME);
@@ -123,7 +187,7 @@ fn cli_loop_interactive(client: KVSClient) -> Result<&'static str> {
         match rl.readline("anna> ") {
             Ok(line) => {
                 rl.add_history_entry(&line);
-                execute_command(&line, &client);
+                execute_command(&line, client);
             },
             Err(_) => break, // Includes CONTROL-C and CONTROL-D exits
         }
@@ -137,13 +201,13 @@ fn cli_loop_interactive(client: KVSClient) -> Result<&'static str> {
 /*
     Enter a loop of command/response for the CLI and interact with the server processes for each
 */
-fn cli_loop_file(client: KVSClient, filename: &str) -> Result<&'static str>{
+fn cli_loop_file(client: &mut KVSClient, filename: &str) -> Result<&'static str>{
     let file = File::open(filename).chain_err(|| format!("Could not open the command_file: {}", filename))?;
     let reader = BufReader::new(file);
 
     for line in reader.lines() {
         if let Ok(ref string) = line {
-            execute_command(&string, &client);
+            execute_command(&string, client);
         }
     }
 
@@ -151,12 +215,74 @@ fn cli_loop_file(client: KVSClient, filename: &str) -> Result<&'static str>{
 }
 
 /*
-    Try to parse and then open a command_file of anna commands
+    Create a client, then try to parse and open a command_file of anna commands or
+    start an interactive session. The client's causal session is saved on exit.
+ */
+fn cli(config: &Config, namespace: Option<&str>, args: Option<&ArgMatches>) -> Result<&'static str> {
+    let session_file = args.and_then(|args| args.value_of("session"));
+    let mut client = KVSClient::new(config, None, session_file, namespace)
+        .chain_err(|| "Could not create anna client")?;
+
+    let result = match args.and_then(|args| args.value_of("command_file")) {
+        None => cli_loop_interactive(&mut client),
+        Some(filename) => cli_loop_file(&mut client, filename)
+    };
+
+    // save the session even if the loop failed, so the causal state it built up is not lost
+    let saved = client.save_session().chain_err(|| "Could not save the client session");
+    let message = result?;
+    saved?;
+    Ok(message)
+}
+
+/*
+    Sweep the catalog of deleted keys, dropping those that have been written again
+ */
+fn sweep(config: &Config, namespace: Option<&str>) -> Result<String> {
+    let mut client = KVSClient::new(config, None, None, namespace)
+        .chain_err(|| "Could not create anna client")?;
+    let report = client.sweep_tombstones().chain_err(|| "Could not sweep deleted keys")?;
+    Ok(format!("{} keys are deleted, {} deleted keys were written again and dropped from the catalog",
+               report.tombstoned.len(), report.resurrected.len()))
+}
+
+/*
+    Tombstone the keys written with a time to live that have expired
+ */
+fn expire_sweep(config: &Config, namespace: Option<&str>) -> Result<String> {
+    let mut client = KVSClient::new(config, None, None, namespace)
+        .chain_err(|| "Could not create anna client")?;
+    let expired = client.expire_sweep().chain_err(|| "Could not sweep expired keys")?;
+    Ok(format!("{} expired keys were deleted", expired.len()))
+}
+
+/*
+    List the keys in the catalog that start with the prefix given
  */
-fn cli_loop(client: KVSClient, args: &ArgMatches) -> Result<&'static str> {
-    match args.value_of("command_file") {
-        None => cli_loop_interactive(client),
-        Some(filename) => cli_loop_file(client, filename)
+fn keys(config: &Config, namespace: Option<&str>, args: Option<&ArgMatches>) -> Result<String> {
+    let mut client = KVSClient::new(config, None, None, namespace)
+        .chain_err(|| "Could not create anna client")?;
+    let prefix = args.and_then(|args| args.value_of("prefix")).unwrap_or("");
+    client.keys(&[prefix]).chain_err(|| "Could not read the key catalog")
+}
+
+/*
+    Show the writes waiting in the journal, or replay them
+ */
+fn journal(config: &Config, namespace: Option<&str>, args: &ArgMatches) -> Result<String> {
+    let mut client = KVSClient::new(config, None, None, namespace)
+        .chain_err(|| "Could not create anna client")?;
+    let (path, pending) = match client.get_journal() {
+        Some(journal) => (journal.path().display().to_string(), journal.pending()),
+        None => bail!("No journal file is configured"),
+    };
+
+    match args.subcommand_name() {
+        Some("flush") => {
+            let replayed = client.flush_journal().chain_err(|| "Could not flush the journal")?;
+            Ok(format!("{} journaled writes were replayed", replayed))
+        }
+        _ => Ok(format!("{} writes are waiting in journal '{}'", pending, path)),
     }
 }
 
@@ -186,11 +312,39 @@ fn get_app() -> App<'static, 'static> {
             .takes_value(true)
             .value_name("CONFIG_FILE")
             .help("Specify the config file to be used"))
+        .arg(Arg::with_name("namespace")
+            .short("n")
+            .long("namespace")
+            .takes_value(true)
+            .value_name("NAMESPACE")
+            .help("Prefix all keys with a namespace, so they do not collide with those of other users"))
         .subcommand(SubCommand::with_name("cli")
             .about("Start an interactive anna CLI session")
             .arg(Arg::with_name("command_file")
                 .index(1)
-                .help("A file where anna commands are read from")))
+                .help("A file where anna commands are read from"))
+            .arg(Arg::with_name("session")
+                .short("s")
+                .long("session")
+                .takes_value(true)
+                .value_name("SESSION_FILE")
+                .help("A file to load the causal session (client id, vector clock and dependencies) from, and save it to on exit")))
+        .subcommand(SubCommand::with_name("sweep")
+            .about("Sweep the catalog of deleted keys, dropping keys that have been written again"))
+        .subcommand(SubCommand::with_name("expire-sweep")
+            .about("Delete the keys written with a time to live that have expired"))
+        .subcommand(SubCommand::with_name("keys")
+            .about("List the keys in the key catalog that start with a prefix")
+            .arg(Arg::with_name("prefix")
+                .index(1)
+                .help("The prefix of the keys to list (all keys if omitted)")))
+        .subcommand(SubCommand::with_name("journal")
+            .about("Show or replay the writes journaled while the cluster was unreachable")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("status")
+                .about("Show the number of writes waiting in the journal"))
+            .subcommand(SubCommand::with_name("flush")
+                .about("Replay the writes waiting in the journal")))
         .subcommand(SubCommand::with_name("start")
             .about("Start anna processes (monitor, route and kvs) in background"))
         .subcommand(SubCommand::with_name("stop")